@@ -1,38 +1,145 @@
-use rand::Rng;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg32;
+use serde::{Deserialize, Serialize};
 use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::str::FromStr;
 use tcod::colors::*;
 use tcod::console::*;
+use tcod::input::{self, Event, Key, Mouse};
+use tcod::line::Line;
 use tcod::map::{FovAlgorithm, Map as FovMap};
+use tcod::pathfinding::AStar;
 use PlayerAction::*;
 
 // actual size of the window
 const SCREEN_WIDTH: i32 = 80;
 const SCREEN_HEIGHT: i32 = 50;
 
-// size of the map
-const MAP_WIDTH: i32 = 80;
-const MAP_HEIGHT: i32 = 43;
+// size of the map, bigger than the screen
+const MAP_WIDTH: i32 = 120;
+const MAP_HEIGHT: i32 = 70;
+
+// size of the part of the map on screen, scrolled around by the camera
+const VIEW_WIDTH: i32 = SCREEN_WIDTH;
+const VIEW_HEIGHT: i32 = SCREEN_HEIGHT - PANEL_HEIGHT;
+// the camera clamps to the map edges, which needs a map at least as big as the view
+const _: () = assert!(MAP_WIDTH >= VIEW_WIDTH && MAP_HEIGHT >= VIEW_HEIGHT);
 
 
 //parameters for dungeon generator
 const ROOM_MAX_SIZE: i32 = 10;
 const ROOM_MIN_SIZE: i32 = 6;
-const MAX_ROOMS: i32 = 30;
+const MAX_ROOMS: i32 = 60;
+const BSP_MIN_LEAF_SIZE: i32 = ROOM_MIN_SIZE + 2; // smallest area a BSP split leaves
+
+// parameters for the cave generator
+const CAVE_WALL_CHANCE: f64 = 0.45; // initial noise
+const CAVE_SMOOTHING_STEPS: i32 = 5;
+const CAVE_MIN_OPEN_FRACTION: f32 = 0.25; // of the map, or the cave is dug again
+const CAVE_REGION_SIZE: i32 = 10; // caves are reported as a grid of regions this big
+
+// connectivity repair: tunnels dug before a map is given up and regenerated
+const MAX_MAP_REPAIRS: usize = 50;
+
+// doors, placed where corridors go through room walls
+const DOOR_CHANCE: f64 = 0.7; // of each doorway getting a door
+const LOCKED_DOOR_CHANCE: f64 = 0.3; // of a level locking one of its doors
+const DOOR_COST: f32 = 2.0; // pathfinding: opening a door takes a turn
+const COLOR_DOOR: Color = Color {
+    r: 150,
+    g: 90,
+    b: 30,
+};
+
+// terrain features: pools, lava flows, chasms and rubble inside rooms
+const TERRAIN_FEATURE_CHANCE: f64 = 0.3; // of each room getting one
+const TERRAIN_FEATURE_MAX_RADIUS: i32 = 2;
+const LAVA_DAMAGE: i32 = 5; // per step in lava
+const FALL_DAMAGE: i32 = 3; // for dropping down a chasm
+const RUBBLE_COST: f32 = 2.0; // pathfinding: rubble takes two turns to cross
+
+// levels generated per seed by `--check-maps`
+const CHECK_MAPS_DEPTH: u32 = 5;
+
+const MAX_ROOM_ITEMS: i32 = 2;
+
+// pathfinding: walking through a tile taken by another creature costs as
+// much as this many free tiles, so monsters prefer short detours
+const BLOCKED_OBJECT_COST: f32 = 10.0;
+const DIAGONAL_COST: f32 = 1.41;
+
+// monster behaviour
+const SLEEPING_CHANCE: f64 = 0.5; // chance that a monster spawns asleep
+const FLEE_HP_FRACTION: f32 = 0.25; // monsters run away below this much health
+
+// items and inventory
+const INVENTORY_SIZE: usize = 26; // one per letter of the menu
+const INVENTORY_WIDTH: i32 = 50;
+const HEAL_AMOUNT: i32 = 4;
 
-const MAX_ROOM_MONSTERS: i32 = 3;
+// experience and level-ups
+const LEVEL_UP_BASE: i32 = 200;
+const LEVEL_UP_FACTOR: i32 = 150;
+const LEVEL_SCREEN_WIDTH: i32 = 40;
 
 // sizes and coordinates relevant for the GUI
 const BAR_WIDTH: i32 = 10;
 const PANEL_HEIGHT: i32 = 7;
 const PANEL_Y: i32 = SCREEN_HEIGHT - PANEL_HEIGHT;
+const MSG_X: i32 = BAR_WIDTH + 2;
+const MSG_WIDTH: i32 = SCREEN_WIDTH - BAR_WIDTH - 2;
+const MSG_HEIGHT: usize = PANEL_HEIGHT as usize - 1;
+const MAX_MESSAGES: usize = 100; // older messages scroll off the log
 
-//FOV algorithm consts
-const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic; // Default FOV algorithm
-const FOV_LIGHT_WALLS: bool = true; // light walls or not
+// the player's torch, it burns down a step every so many turns and is
+// replaced on every new level
 const TORCH_RADIUS: i32 = 10;
+const TORCH_MIN_RADIUS: i32 = 3;
+const TORCH_MAX_RADIUS: i32 = 20; // largest the options menu goes
+const TORCH_BURN_TURNS: u32 = 150;
+const OPTIONS_WIDTH: i32 = 40;
+
+// lighting: a tile is only seen if enough light falls on it
+const TORCH_COLOR: Color = Color {
+    r: 255,
+    g: 210,
+    b: 140,
+};
+const ROOM_LIGHT: Color = Color {
+    r: 200,
+    g: 200,
+    b: 190,
+};
+const MIN_VISIBLE_LIGHT: u32 = 40; // r + g + b of the light on a tile
+const BRAZIER_CHANCE: f64 = 0.3; // of a dark room getting a brazier
+const BRAZIER_LIGHT: Light = Light {
+    color: Color {
+        r: 255,
+        g: 120,
+        b: 40,
+    },
+    radius: 6,
+};
 
 // player is first object
 const PLAYER: usize = 0;
+
+// monster definitions
+const MONSTERS_FILE: &str = "monsters.toml";
+
+// key bindings, the built-in ones are used when the file is missing
+const KEYBINDINGS_FILE: &str = "keybindings.toml";
+const DEFAULT_KEYBINDINGS: &str = include_str!("../keybindings.toml");
+
+// save file, bumped whenever the saved state changes shape
+const SAVE_FILE: &str = "savegame";
+const SAVE_VERSION: u32 = 14;
 const COLOR_DARK_WALL: Color = Color { r: 0, g: 0, b: 100 };
 const COLOR_LIGHT_WALL: Color = Color {
     r: 130,
@@ -54,8 +161,144 @@ const COLOR_LIGHT_GROUND: Color = Color {
 
 type Map = Vec<Vec<Tile>>;
 
+/// The one random number generator of a run. Everything random about the
+/// dungeon is drawn from it, so the same seed gives the same dungeon
+type GameRng = Pcg32;
+
+/// Everything the simulation needs to run a turn. Nothing in here touches
+/// the window, so game logic can run headless
+#[derive(Serialize, Deserialize)]
 struct Game {
     map: Map,
+    messages: Messages,
+    inventory: Vec<Object>,
+    dungeon_level: u32,
+    // loaded from the monsters file, not saved
+    #[serde(skip)]
+    monsters: Vec<MonsterTemplate>,
+    generator_params: GeneratorParams,
+    // rebuilt from the map after loading
+    #[serde(skip, default = "empty_fov")]
+    fov: FovMap,
+    // light falling on each tile, recomputed along with the FOV
+    #[serde(skip)]
+    light: Vec<Vec<Color>>,
+    fov_settings: FovSettings,
+    torch_fuel: u32, // turns until the torch burns down a step
+    seed: u64,
+    rng: GameRng,
+}
+
+/// How the player's FOV is computed. Change it through `set_fov_settings`,
+/// so the FOV follows straight away
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct FovSettings {
+    algorithm: FovAlgo,
+    light_walls: bool,
+    torch_radius: i32,
+}
+
+impl Default for FovSettings {
+    fn default() -> Self {
+        FovSettings {
+            algorithm: FovAlgo::Basic,
+            light_walls: true,
+            torch_radius: TORCH_RADIUS,
+        }
+    }
+}
+
+/// The FOV algorithms tcod offers, in an order to cycle through
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum FovAlgo {
+    Basic,
+    Diamond,
+    Shadow,
+    /// permissiveness from 0 to 8
+    Permissive(u8),
+    Restrictive,
+}
+
+impl FovAlgo {
+    fn algorithm(self) -> FovAlgorithm {
+        use FovAlgo::*;
+        match self {
+            Basic => FovAlgorithm::Basic,
+            Diamond => FovAlgorithm::Diamond,
+            Shadow => FovAlgorithm::Shadow,
+            Permissive(0) => FovAlgorithm::Permissive0,
+            Permissive(1) => FovAlgorithm::Permissive1,
+            Permissive(2) => FovAlgorithm::Permissive2,
+            Permissive(3) => FovAlgorithm::Permissive3,
+            Permissive(4) => FovAlgorithm::Permissive4,
+            Permissive(5) => FovAlgorithm::Permissive5,
+            Permissive(6) => FovAlgorithm::Permissive6,
+            Permissive(7) => FovAlgorithm::Permissive7,
+            Permissive(_) => FovAlgorithm::Permissive8,
+            Restrictive => FovAlgorithm::Restrictive,
+        }
+    }
+
+    fn next(self) -> Self {
+        use FovAlgo::*;
+        match self {
+            Basic => Diamond,
+            Diamond => Shadow,
+            Shadow => Permissive(0),
+            Permissive(level) if level < 8 => Permissive(level + 1),
+            Permissive(_) => Restrictive,
+            Restrictive => Basic,
+        }
+    }
+}
+
+impl fmt::Display for FovAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FovAlgo::Basic => write!(f, "basic"),
+            FovAlgo::Diamond => write!(f, "diamond"),
+            FovAlgo::Shadow => write!(f, "shadow"),
+            FovAlgo::Permissive(level) => write!(f, "permissive {}", level),
+            FovAlgo::Restrictive => write!(f, "restrictive"),
+        }
+    }
+}
+
+impl Game {
+    /// whether the player sees a tile: in their FOV, and lit well enough
+    fn is_visible(&self, x: i32, y: i32) -> bool {
+        let light = self.light[x as usize][y as usize];
+        self.fov.is_in_fov(x, y)
+            && u32::from(light.r) + u32::from(light.g) + u32::from(light.b) >= MIN_VISIBLE_LIGHT
+    }
+}
+
+/// Log of the messages shown in the bottom panel, oldest first
+#[derive(Serialize, Deserialize)]
+struct Messages {
+    messages: Vec<(String, Color)>,
+}
+
+impl Messages {
+    pub fn new() -> Self {
+        Self { messages: vec![] }
+    }
+
+    /// add the new message as a tuple, with the text and the color
+    pub fn add<T: Into<String>>(&mut self, message: T, color: Color) {
+        self.messages.push((message.into(), color));
+
+        // drop the oldest messages once the log is full
+        if self.messages.len() > MAX_MESSAGES {
+            let overflow = self.messages.len() - MAX_MESSAGES;
+            self.messages.drain(0..overflow);
+        }
+    }
+
+    /// Create a `DoubleEndedIterator` over the messages
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &(String, Color)> {
+        self.messages.iter()
+    }
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -65,46 +308,265 @@ enum PlayerAction {
     Exit,
 }
 
-fn player_death(player: &mut Object) {
+/// Something the player can bind a key to
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Command {
+    MoveNorth,
+    MoveSouth,
+    MoveWest,
+    MoveEast,
+    MoveNorthWest,
+    MoveNorthEast,
+    MoveSouthWest,
+    MoveSouthEast,
+    Wait,
+    PickUp,
+    Inventory,
+    Drop,
+    Descend,
+    CloseDoor,
+    Options,
+    Look,
+}
+
+impl Command {
+    /// the step taken by a movement command
+    fn direction(self) -> Option<(i32, i32)> {
+        use Command::*;
+        match self {
+            MoveNorth => Some((0, -1)),
+            MoveSouth => Some((0, 1)),
+            MoveWest => Some((-1, 0)),
+            MoveEast => Some((1, 0)),
+            MoveNorthWest => Some((-1, -1)),
+            MoveNorthEast => Some((1, -1)),
+            MoveSouthWest => Some((-1, 1)),
+            MoveSouthEast => Some((1, 1)),
+            Wait | PickUp | Inventory | Drop | Descend | CloseDoor | Options | Look => None,
+        }
+    }
+}
+
+/// Which command each key triggers, by key name (see `key_name`)
+struct Keybindings {
+    keys: HashMap<String, Command>,
+}
+
+impl Keybindings {
+    fn parse(config: &str) -> Result<Self, Box<dyn Error>> {
+        let keys = toml::from_str(config)?;
+        Ok(Keybindings { keys })
+    }
+
+    /// load the bindings from a file, falling back to the built-in ones
+    /// when there is no such file
+    fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        if fs::metadata(path).is_err() {
+            return Ok(Self::default());
+        }
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    fn command_for(&self, key: tcod::input::Key) -> Option<Command> {
+        key_name(key).and_then(|name| self.keys.get(&name).cloned())
+    }
+}
+
+impl Default for Keybindings {
+    fn default() -> Self {
+        Self::parse(DEFAULT_KEYBINDINGS).expect("the built-in key bindings are valid")
+    }
+}
+
+/// Name of a key in the bindings file: the character for letters and
+/// symbols, the tcod `KeyCode` name for everything else.
+///
+/// Letters and symbols are read from the key press itself (`Char` and its
+/// `printable`), which both `wait_for_keypress` and `check_for_event` report.
+/// The text input event that follows it is ignored, so a key never fires twice
+fn key_name(key: tcod::input::Key) -> Option<String> {
+    use tcod::input::KeyCode::*;
+    match key.code {
+        Text | NoKey => None,
+        Char if key.shift => Some(shifted(key.printable).to_string()),
+        Char => Some(key.printable.to_string()),
+        code => Some(format!("{:?}", code)),
+    }
+}
+
+/// The character shift turns a key into. The key press only reports the
+/// unshifted key, this assumes a US layout for the symbols
+fn shifted(c: char) -> char {
+    const SYMBOLS: [(char, char); 21] = [
+        ('`', '~'), ('1', '!'), ('2', '@'), ('3', '#'), ('4', '$'), ('5', '%'),
+        ('6', '^'), ('7', '&'), ('8', '*'), ('9', '('), ('0', ')'), ('-', '_'),
+        ('=', '+'), ('[', '{'), (']', '}'), ('\\', '|'), (';', ':'), ('\'', '"'),
+        (',', '<'), ('.', '>'), ('/', '?'),
+    ];
+    SYMBOLS
+        .iter()
+        .find(|&&(key, _)| key == c)
+        .map_or(c.to_ascii_uppercase(), |&(_, symbol)| symbol)
+}
+
+fn player_death(player: &mut Object, game: &mut Game) {
     // player has died
-    println!("You died!!");
+    game.messages.add("You died!!", RED);
 
     // transform into corpse!
     player.char = '%';
     player.color = DARK_RED;
 }
 
-fn monster_death(monster: &mut Object) { 
-    println!("{} is dead! ", monster.name);
+fn monster_death(monster: &mut Object, game: &mut Game) { 
+    game.messages.add(
+        format!(
+            "{} is dead! You gain {} experience points.",
+            monster.name,
+            monster.fighter.map_or(0, |f| f.xp)
+        ),
+        ORANGE,
+    );
     monster.char = '%';
     monster.color = DARK_RED;
     monster.blocks = false;
     monster.fighter = None;
     monster.ai = None;
+    monster.light = None;
 }
 
 /// Map tile and its properties
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 struct Tile {
     blocked: bool,
     explored: bool,
     block_sight: bool,
+    lit: bool, // part of a lit room
+    terrain: Terrain,
+    door: Option<Door>,
+}
+
+/// What the ground of a tile is made of
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Terrain {
+    Floor,
+    Wall,
+    /// only swimmers can cross it
+    DeepWater,
+    /// burns whoever steps in
+    Lava,
+    /// drops whoever steps in to the next level
+    Chasm,
+    /// takes an extra turn to climb over
+    Rubble,
+}
+
+impl Terrain {
+    /// background colors, lit and dark
+    fn colors(self) -> (Color, Color) {
+        use Terrain::*;
+        match self {
+            Floor => (COLOR_LIGHT_GROUND, COLOR_DARK_GROUND),
+            Wall => (COLOR_LIGHT_WALL, COLOR_DARK_WALL),
+            DeepWater => (Color { r: 30, g: 70, b: 200 }, Color { r: 10, g: 20, b: 90 }),
+            Lava => (Color { r: 220, g: 70, b: 0 }, Color { r: 90, g: 30, b: 30 }),
+            Chasm => (Color { r: 20, g: 15, b: 10 }, Color { r: 5, g: 5, b: 20 }),
+            Rubble => (COLOR_LIGHT_GROUND, COLOR_DARK_GROUND),
+        }
+    }
+
+    /// what is drawn over the background, if anything
+    fn glyph(self) -> Option<(char, Color)> {
+        use Terrain::*;
+        match self {
+            Floor | Wall | Chasm => None,
+            DeepWater => Some(('~', LIGHT_BLUE)),
+            Lava => Some(('~', YELLOW)),
+            Rubble => Some((':', DARK_GREY)),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        use Terrain::*;
+        match self {
+            Floor => "Floor",
+            Wall => "Wall",
+            DeepWater => "Deep water",
+            Lava => "Lava",
+            Chasm => "Chasm",
+            Rubble => "Rubble",
+        }
+    }
+
+    /// whether nobody in their right mind would stand on it
+    fn hazardous(self) -> bool {
+        self == Terrain::Lava || self == Terrain::Chasm
+    }
+}
+
+/// State of a door tile. Closed and locked doors block movement and sight
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Door {
+    Open,
+    Closed,
+    Locked,
 }
 
 impl Tile {
     pub fn empty() -> Self {
+        Tile::new(Terrain::Floor)
+    }
+
+    pub fn wall() -> Self {
+        Tile::new(Terrain::Wall)
+    }
+
+    /// deep water counts as blocked, swimmers are let through by `can_enter`
+    pub fn new(terrain: Terrain) -> Self {
         Tile {
-            blocked: false,
+            blocked: terrain == Terrain::Wall || terrain == Terrain::DeepWater,
             explored: false,
-            block_sight: false,
+            block_sight: terrain == Terrain::Wall,
+            lit: false,
+            terrain,
+            door: None,
         }
     }
 
-    pub fn wall() -> Self {
+    pub fn door(state: Door) -> Self {
+        let closed = state != Door::Open;
         Tile {
-            blocked: true,
+            blocked: closed,
             explored: false,
-            block_sight: true,
+            block_sight: closed,
+            lit: false,
+            terrain: Terrain::Floor,
+            door: Some(state),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self.door {
+            Some(Door::Open) => "Open door",
+            Some(Door::Closed) => "Closed door",
+            Some(Door::Locked) => "Locked door",
+            None => self.terrain.name(),
+        }
+    }
+
+    /// whether it is safe to stand on: not blocked, not lava or a chasm
+    pub fn walkable(&self) -> bool {
+        !self.blocked && !self.terrain.hazardous()
+    }
+
+    /// whether something could get through, opening doors on the way.
+    /// Locked doors only give way to a key
+    pub fn passable(&self, with_key: bool) -> bool {
+        match self.door {
+            Some(Door::Locked) => with_key,
+            Some(_) => true,
+            None => self.walkable(),
         }
     }
 }
@@ -112,59 +574,229 @@ impl Tile {
 const LIMIT_FPS: i32 = 20; // 20 frames-per-second maximum
 
 // combat-related properties and methods (for player and various monsters)
-#[derive(Clone, Copy, Debug, PartialEq)]
+// the base stats leave out equipment, combat goes through `Object::power` & co.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 struct Fighter {
-    max_hp: i32, 
+    base_max_hp: i32, 
     hp: i32, 
-    defence: i32,
-    power: i32,
+    base_defence: i32,
+    base_power: i32,
+    xp: i32, // for the player: earned so far, for monsters: granted to their killer
     on_death: DeathCallback,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 enum DeathCallback {
     Player,
     Monster,
 }
 
 impl DeathCallback {
-    fn callback(self, object: &mut Object){
+    fn callback(self, object: &mut Object, game: &mut Game){
         use DeathCallback::*;
-        let callback: fn(&mut Object) = match self {
+        let callback: fn(&mut Object, &mut Game) = match self {
             Player => player_death,
             Monster => monster_death,
         };
-        callback(object);
+        callback(object, game);
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// What a monster is up to. Each turn the current state decides what the
+/// monster does and which state it is in next
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 enum Ai {
-    Basic, 
+    /// does nothing until the player comes into view
+    Sleeping,
+    /// roams at random, nobody to chase
+    Wandering,
+    /// chases the player, or the place the player was last seen
+    Hunting { last_seen: (i32, i32) },
+    /// badly hurt, runs away from the player
+    Fleeing,
 }
 
+/// What an object does when it is used from the inventory
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Item {
+    Heal,
+    Sword,
+    Shield,
+    Armor,
+    Key,
+}
 
-fn ai_take_turn(monster_id : usize, tcod: &Tcod, game: &Game, objects: &mut [Object]) {
-    // monster's turn
+enum UseResult {
+    UsedUp,
+    UsedAndKept,
+    Cancelled,
+}
+
+/// An object that can be equipped, yielding bonuses
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Equipment {
+    slot: Slot,
+    equipped: bool,
+    max_hp_bonus: i32,
+    defence_bonus: i32,
+    power_bonus: i32,
+}
+
+/// Where a piece of equipment is worn, one item per slot
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Slot {
+    MainHand,
+    OffHand,
+    Body,
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Slot::MainHand => write!(f, "main hand"),
+            Slot::OffHand => write!(f, "off hand"),
+            Slot::Body => write!(f, "body"),
+        }
+    }
+}
+
+
+fn ai_take_turn(monster_id : usize, game: &mut Game, objects: &mut [Object]) {
+    // still climbing over rubble
+    if objects[monster_id].slowed {
+        objects[monster_id].slowed = false;
+        return;
+    }
 
-    let (monster_x, monster_y) = objects[monster_id].pos();
-    if tcod.fov.is_in_fov(monster_x,monster_y) {
-        if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
-            // move towards player
-            let (player_x, player_y) = objects[PLAYER].pos();
-            move_towards(monster_id, player_x, player_y, &game.map, objects);
+    // monster's turn
+    use Ai::*;
+    let start = objects[monster_id].pos();
+    if let Some(ai) = objects[monster_id].ai.take() {
+        let new_ai = match ai {
+            Sleeping => ai_sleeping(monster_id, game, objects),
+            Wandering => ai_wandering(monster_id, game, objects),
+            Hunting { last_seen } => ai_hunting(monster_id, last_seen, game, objects),
+            Fleeing => ai_fleeing(monster_id, game, objects),
+        };
+        // a monster that died on its own turn stays a corpse
+        if objects[monster_id].alive {
+            objects[monster_id].ai = Some(new_ai);
         }
-        // close enough to attack the PLAYEr
-        else if objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
-            let (monster, player) = mut_two(monster_id, PLAYER, objects);
-            monster.attack(player);
+    }
+
+    let (x, y) = objects[monster_id].pos();
+    if (x, y) != start && game.map[x as usize][y as usize].terrain == Terrain::Rubble {
+        objects[monster_id].slowed = true;
+    }
+}
+
+/// whether the monster notices the player this turn: with its own eyes, or
+/// by bumping into them when it is right next to them
+fn can_see_player(monster_id: usize, game: &Game, objects: &[Object]) -> bool {
+    let monster = &objects[monster_id];
+    let player = &objects[PLAYER];
+    if !player.alive {
+        return false;
+    }
+    let distance = monster.distance_to(player);
+    distance < 2.0
+        || (distance <= monster.vision_radius as f32
+            && line_of_sight(monster.pos(), player.pos(), &game.map))
+}
+
+/// whether nothing blocks sight between two points, ignoring the end points
+fn line_of_sight(from: (i32, i32), to: (i32, i32), map: &Map) -> bool {
+    Line::new(from, to)
+        .take_while(|&pos| pos != to)
+        .all(|(x, y)| !map[x as usize][y as usize].block_sight)
+}
+
+/// whether the monster is hurt badly enough to run away
+fn wants_to_flee(monster: &Object, game: &Game) -> bool {
+    monster.fighter.is_some_and(|f| {
+        (f.hp as f32) < monster.max_hp(game) as f32 * FLEE_HP_FRACTION
+    })
+}
+
+fn ai_sleeping(monster_id: usize, game: &mut Game, objects: &mut [Object]) -> Ai {
+    if can_see_player(monster_id, game, objects) {
+        // woken up: go straight for the player
+        game.messages.add(
+            format!("The {} wakes up!", objects[monster_id].name),
+            LIGHT_ORANGE,
+        );
+        Ai::Hunting { last_seen: objects[PLAYER].pos() }
+    } else {
+        Ai::Sleeping
+    }
+}
+
+fn ai_wandering(monster_id: usize, game: &mut Game, objects: &mut [Object]) -> Ai {
+    if can_see_player(monster_id, game, objects) {
+        return ai_hunting(monster_id, objects[PLAYER].pos(), game, objects);
+    }
+
+    // take a random step, which may well bump into a wall and go nowhere
+    let dx = game.rng.gen_range(-1, 2);
+    let dy = game.rng.gen_range(-1, 2);
+    move_by(monster_id, dx, dy, &game.map, objects);
+    Ai::Wandering
+}
+
+fn ai_hunting(monster_id: usize, last_seen: (i32, i32), game: &mut Game, objects: &mut [Object]) -> Ai {
+    if wants_to_flee(&objects[monster_id], game) {
+        return ai_fleeing(monster_id, game, objects);
+    }
+
+    if !can_see_player(monster_id, game, objects) {
+        // head for the last place the player was seen, and give up once there
+        if objects[monster_id].pos() == last_seen {
+            return Ai::Wandering;
         }
+        move_astar(monster_id, last_seen.0, last_seen.1, game, objects);
+        return Ai::Hunting { last_seen };
+    }
+
+    let (player_x, player_y) = objects[PLAYER].pos();
+    if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
+        // move towards player
+        move_astar(monster_id, player_x, player_y, game, objects);
+    }
+    // close enough to attack the PLAYEr
+    else if objects[PLAYER].fighter.is_some_and(|f| f.hp > 0) {
+        let (monster, player) = mut_two(monster_id, PLAYER, objects);
+        monster.attack(player, game);
+    }
+    Ai::Hunting { last_seen: (player_x, player_y) }
+}
+
+fn ai_fleeing(monster_id: usize, game: &mut Game, objects: &mut [Object]) -> Ai {
+    if !can_see_player(monster_id, game, objects) {
+        // out of sight, out of mind
+        return Ai::Wandering;
+    }
+
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let moved = move_away(monster_id, player_x, player_y, &game.map, objects);
+    if !moved && objects[monster_id].distance_to(&objects[PLAYER]) < 2.0 {
+        // cornered: fight back
+        let (monster, player) = mut_two(monster_id, PLAYER, objects);
+        monster.attack(player, game);
     }
+    Ai::Fleeing
+}
+
+/// Light given off by an object, fading out towards its radius
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Light {
+    color: Color,
+    radius: i32,
 }
 
 /// This template Object can be used for multiple items in the game..
 /// It is represented by a character on the screen
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct Object {
     x: i32,
     y: i32,
@@ -173,8 +805,16 @@ struct Object {
     name: String, 
     blocks: bool,
     alive: bool,
+    always_visible: bool,
+    level: i32,
+    vision_radius: i32, // how far a monster sees, 0 for blind ones
+    swims: bool,        // can cross deep water
+    slowed: bool,       // loses its next turn, after climbing over rubble
+    light: Option<Light>,
     fighter: Option<Fighter>,
     ai: Option<Ai>,
+    item: Option<Item>,
+    equipment: Option<Equipment>,
 }
 
 impl Object {
@@ -187,8 +827,16 @@ impl Object {
             name: name.into(),
             blocks: blocks,
             alive: false,
+            always_visible: false,
+            level: 1,
+            vision_radius: 0,
+            swims: false,
+            slowed: false,
+            light: None,
             fighter: None,
             ai: None, 
+            item: None,
+            equipment: None,
         }
     }
 
@@ -202,19 +850,27 @@ impl Object {
         self.y = y;
     }
 
-    // rendering the Object
-    pub fn draw(&self, con: &mut dyn Console) {
-        con.set_default_background(self.color);
-        con.put_char(self.x, self.y, self.char, BackgroundFlag::None);
+    // rendering the Object, if the camera can see it
+    pub fn draw(&self, con: &mut dyn Console, camera: Camera) {
+        if let Some((x, y)) = camera.to_screen(self.x, self.y) {
+            con.set_default_foreground(self.color);
+            con.put_char(x, y, self.char, BackgroundFlag::None);
+        }
     }
 
     pub fn distance_to(&self, other: &Object) -> f32 { 
-        let dx = other.x - self.x ; 
-        let dy = other.y - self.y ;
+        self.distance(other.x, other.y)
+    }
+
+    /// return the distance to some coordinates
+    pub fn distance(&self, x: i32, y: i32) -> f32 {
+        let dx = x - self.x ;
+        let dy = y - self.y ;
         ((dx.pow(2) + dy.pow(2)) as f32).sqrt()
     }
 
-    pub fn take_damage(&mut self, damage: i32) {
+    /// Returns the experience granted for the kill, if this damage was fatal
+    pub fn take_damage(&mut self, damage: i32, game: &mut Game) -> Option<i32> {
         // if possible, cause damage
         if let Some(fighter) = self.fighter.as_mut() { 
             if damage > 0 {
@@ -226,29 +882,121 @@ impl Object {
         if let Some(fighter) = self.fighter { 
             if fighter.hp <= 0 {
                 self.alive = false; 
-                fighter.on_death.callback(self);
+                fighter.on_death.callback(self, game);
+                return Some(fighter.xp);
+            }
+        }
+        None
+    }
+
+    /// heal by the given amount, without going over the maximum
+    pub fn heal(&mut self, amount: i32, game: &Game) {
+        let max_hp = self.max_hp(game);
+        if let Some(ref mut fighter) = self.fighter {
+            fighter.hp += amount;
+            if fighter.hp > max_hp {
+                fighter.hp = max_hp;
+            }
+        }
+    }
+
+    /// effective stats: the fighter's base plus every equipped bonus
+    pub fn power(&self, game: &Game) -> i32 {
+        let base_power = self.fighter.map_or(0, |f| f.base_power);
+        let bonus: i32 = self.get_all_equipped(game).iter().map(|e| e.power_bonus).sum();
+        base_power + bonus
+    }
+
+    pub fn defence(&self, game: &Game) -> i32 {
+        let base_defence = self.fighter.map_or(0, |f| f.base_defence);
+        let bonus: i32 = self.get_all_equipped(game).iter().map(|e| e.defence_bonus).sum();
+        base_defence + bonus
+    }
+
+    pub fn max_hp(&self, game: &Game) -> i32 {
+        let base_max_hp = self.fighter.map_or(0, |f| f.base_max_hp);
+        let bonus: i32 = self.get_all_equipped(game).iter().map(|e| e.max_hp_bonus).sum();
+        base_max_hp + bonus
+    }
+
+    /// returns a list of equipped items. Only the player carries an inventory
+    pub fn get_all_equipped(&self, game: &Game) -> Vec<Equipment> {
+        if self.name == "player" {
+            game.inventory
+                .iter()
+                .filter_map(|item| item.equipment)
+                .filter(|equipment| equipment.equipped)
+                .collect()
+        } else {
+            vec![] // other objects have no equipment
+        }
+    }
+
+    /// Equip object and show a message about it
+    pub fn equip(&mut self, messages: &mut Messages) {
+        if let Some(ref mut equipment) = self.equipment {
+            if !equipment.equipped {
+                equipment.equipped = true;
+                messages.add(
+                    format!("Equipped {} on {}.", self.name, equipment.slot),
+                    LIGHT_GREEN,
+                );
+            }
+        } else {
+            messages.add(
+                format!("Can't equip {:?} because it's not an Equipment.", self.name),
+                RED,
+            );
+        }
+    }
+
+    /// Unequip object and show a message about it
+    pub fn unequip(&mut self, messages: &mut Messages) {
+        if let Some(ref mut equipment) = self.equipment {
+            if equipment.equipped {
+                equipment.equipped = false;
+                messages.add(
+                    format!("Unequipped {} from {}.", self.name, equipment.slot),
+                    LIGHT_YELLOW,
+                );
             }
+        } else {
+            messages.add(
+                format!("Can't unequip {:?} because it's not an Equipment.", self.name),
+                RED,
+            );
         }
     }
 
-    pub fn attack(&mut self, target: &mut Object){
+    pub fn attack(&mut self, target: &mut Object, game: &mut Game){
         // simple attack formula
-        let damage = self.fighter.map_or(0, |f| f.power) - target.fighter.map_or(0, |f| f.defence);
+        let damage = self.power(game) - target.defence(game);
         
         // reflect the damage
         if damage > 0 { 
             // PLAYER takes some damage
-            println!(
-                "{} attacks {} for {} damage." ,
-                self.name, target.name, damage
+            game.messages.add(
+                format!(
+                    "{} attacks {} for {} damage." ,
+                    self.name, target.name, damage
+                ),
+                WHITE,
             );
-            target.take_damage(damage);
+            if let Some(xp) = target.take_damage(damage, game) {
+                // yield experience to the killer
+                if let Some(fighter) = self.fighter.as_mut() {
+                    fighter.xp += xp;
+                }
+            }
         }
         else
         {
-            println!(
-                "{} attacks {} to no effect",
-                self.name, target.name
+            game.messages.add(
+                format!(
+                    "{} attacks {} to no effect",
+                    self.name, target.name
+                ),
+                WHITE,
             );
         }
     }
@@ -297,11 +1045,54 @@ fn create_room(room: Rect, map: &mut Map) {
     }
 }
 
+/// The window and the consoles drawn onto it
 struct Tcod {
     root: Root,
     con: Offscreen,
     panel: Offscreen,
-    fov: FovMap,
+    camera: Camera,
+    keys: Keybindings,
+    key: Key,     // pressed this frame, if any
+    mouse: Mouse, // where the mouse was last seen
+}
+
+/// Which part of the map is on screen: the map position of the view's top
+/// left corner. Everything drawn or pointed at on the map goes through it
+#[derive(Clone, Copy, Debug, Default)]
+struct Camera {
+    x: i32,
+    y: i32,
+}
+
+impl Camera {
+    /// centred on the given map position, without scrolling past the map edges
+    fn following(x: i32, y: i32) -> Self {
+        Camera {
+            x: (x - VIEW_WIDTH / 2).clamp(0, MAP_WIDTH - VIEW_WIDTH),
+            y: (y - VIEW_HEIGHT / 2).clamp(0, MAP_HEIGHT - VIEW_HEIGHT),
+        }
+    }
+
+    /// where a map position shows up in the view, if it is in view at all
+    fn to_screen(self, x: i32, y: i32) -> Option<(i32, i32)> {
+        let (screen_x, screen_y) = (x - self.x, y - self.y);
+        if screen_x >= 0 && screen_y >= 0 && screen_x < VIEW_WIDTH && screen_y < VIEW_HEIGHT {
+            Some((screen_x, screen_y))
+        } else {
+            None
+        }
+    }
+
+    /// the map position under a point of the view, if it is on the map
+    fn to_map(self, screen_x: i32, screen_y: i32) -> Option<(i32, i32)> {
+        let in_view = screen_x >= 0 && screen_y >= 0 && screen_x < VIEW_WIDTH && screen_y < VIEW_HEIGHT;
+        let (x, y) = (screen_x + self.x, screen_y + self.y);
+        if in_view && x < MAP_WIDTH && y < MAP_HEIGHT {
+            Some((x, y))
+        } else {
+            None
+        }
+    }
 }
 
 fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
@@ -321,7 +1112,7 @@ fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
 // movement
 fn move_by(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object]) {
     let (x,y) = objects[id].pos();
-    if !is_blocked(x + dx, y + dy, map, objects) {
+    if !is_step_blocked(id, dx, dy, map, objects) {
         objects[id].set_pos(x + dx, y + dy);
     }
 }
@@ -340,9 +1131,185 @@ fn move_towards(id: usize, target_x: i32, target_y: i32, map: &Map, objects: &mu
     move_by(id, dx, dy, map, objects);
 }
 
+/// First step of the cheapest path for object `id` to `to`. Tiles it can't
+/// enter are impassable, other blocking objects only make a tile more expensive
+fn next_step_towards(id: usize, to: (i32, i32), map: &Map, objects: &[Object]) -> Option<(i32, i32)> {
+    let mut path = AStar::new_from_callback(
+        MAP_WIDTH,
+        MAP_HEIGHT,
+        |(from_x, from_y), (x, y)| {
+            let tile = &map[x as usize][y as usize];
+            let enterable = if tile.door.is_some() {
+                tile.passable(false)
+            } else {
+                can_enter(id, tile, objects)
+            };
+            if !enterable
+                || squeezes_between_walls(from_x, from_y, x - from_x, y - from_y, map)
+            {
+                0.0 // impassable
+            } else if tile.door == Some(Door::Closed) {
+                DOOR_COST
+            } else if tile.terrain == Terrain::Rubble {
+                RUBBLE_COST
+            } else if (x, y) != to && objects.iter().any(|o| o.blocks && o.pos() == (x, y)) {
+                BLOCKED_OBJECT_COST
+            } else {
+                1.0
+            }
+        },
+        DIAGONAL_COST,
+    );
+
+    if path.find(objects[id].pos(), to) {
+        path.walk_one_step(false)
+    } else {
+        None
+    }
+}
+
+/// Move one step along the A* path to the target, or straight at it when
+/// there is no path at all. A closed door in the way gets opened instead
+fn move_astar(id: usize, target_x: i32, target_y: i32, game: &mut Game, objects: &mut [Object]) {
+    let (x, y) = objects[id].pos();
+    match next_step_towards(id, (target_x, target_y), &game.map, objects) {
+        Some((next_x, next_y))
+            if game.map[next_x as usize][next_y as usize].door == Some(Door::Closed) =>
+        {
+            set_door(next_x, next_y, Door::Open, game);
+        }
+        Some((next_x, next_y)) => move_by(id, next_x - x, next_y - y, &game.map, objects),
+        None => move_towards(id, target_x, target_y, &game.map, objects),
+    }
+}
+
+/// Change the state of a door, keeping the FOV map in sync
+fn set_door(x: i32, y: i32, state: Door, game: &mut Game) {
+    let Tile { explored, lit, .. } = game.map[x as usize][y as usize];
+    game.map[x as usize][y as usize] = Tile {
+        explored,
+        lit,
+        ..Tile::door(state)
+    };
+    update_fov_tile(x, y, &game.map, &mut game.fov);
+}
+
+/// The player bumped into a door: open it, unlocking it first if they carry a key
+fn player_open_door(x: i32, y: i32, game: &mut Game) {
+    match game.map[x as usize][y as usize].door {
+        Some(Door::Closed) => {
+            set_door(x, y, Door::Open, game);
+            game.messages.add("You open the door.", WHITE);
+        }
+        Some(Door::Locked) => {
+            let key = game
+                .inventory
+                .iter()
+                .position(|item| item.item == Some(Item::Key));
+            match key {
+                Some(key) => {
+                    game.inventory.remove(key);
+                    set_door(x, y, Door::Open, game);
+                    game.messages
+                        .add("You unlock the door with your key.", LIGHT_YELLOW);
+                }
+                None => {
+                    game.messages.add("The door is locked.", LIGHT_GREY);
+                }
+            }
+        }
+        Some(Door::Open) | None => {}
+    }
+}
+
+/// Close an open door next to the player. Returns false if there is none,
+/// or it is held open by someone standing in it
+fn close_door(game: &mut Game, objects: &[Object]) -> bool {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            let (x, y) = (player_x + dx, player_y + dy);
+            if game.map[x as usize][y as usize].door != Some(Door::Open) {
+                continue;
+            }
+            if objects.iter().any(|object| object.blocks && object.pos() == (x, y)) {
+                game.messages
+                    .add("Something is standing in the doorway.", LIGHT_GREY);
+                return false;
+            }
+            set_door(x, y, Door::Closed, game);
+            game.messages.add("You close the door.", WHITE);
+            return true;
+        }
+    }
+    game.messages
+        .add("There is no open door next to you.", LIGHT_GREY);
+    false
+}
+
+/// Step to the free neighbouring tile furthest from the given point.
+/// Returns false if no step gets any further away
+fn move_away(id: usize, from_x: i32, from_y: i32, map: &Map, objects: &mut [Object]) -> bool {
+    let (x, y) = objects[id].pos();
+    let mut best = None;
+    let mut best_distance = objects[id].distance(from_x, from_y);
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            if (dx, dy) == (0, 0) || is_step_blocked(id, dx, dy, map, objects) {
+                continue;
+            }
+            let dist_x = from_x - (x + dx);
+            let dist_y = from_y - (y + dy);
+            let distance = ((dist_x.pow(2) + dist_y.pow(2)) as f32).sqrt();
+            if distance > best_distance {
+                best = Some((dx, dy));
+                best_distance = distance;
+            }
+        }
+    }
+
+    match best {
+        Some((dx, dy)) => {
+            move_by(id, dx, dy, map, objects);
+            true
+        }
+        None => false,
+    }
+}
+
+/// whether an object's step by (dx, dy) is blocked, either at the
+/// destination or by squeezing diagonally between two walls
+fn is_step_blocked(id: usize, dx: i32, dy: i32, map: &Map, objects: &[Object]) -> bool {
+    let (x, y) = objects[id].pos();
+    let (to_x, to_y) = (x + dx, y + dy);
+    !can_enter(id, &map[to_x as usize][to_y as usize], objects)
+        || objects
+            .iter()
+            .any(|object| object.blocks && object.pos() == (to_x, to_y))
+        || squeezes_between_walls(x, y, dx, dy, map)
+}
+
+/// whether an object may step on a tile at all. Swimmers cross deep water,
+/// and only the player is reckless enough to walk into lava or a chasm
+fn can_enter(id: usize, tile: &Tile, objects: &[Object]) -> bool {
+    match tile.terrain {
+        Terrain::DeepWater => objects[id].swims,
+        Terrain::Lava | Terrain::Chasm => id == PLAYER,
+        _ => !tile.blocked,
+    }
+}
+
+/// a diagonal step with walls on both sides of the corner it cuts
+fn squeezes_between_walls(x: i32, y: i32, dx: i32, dy: i32, map: &Map) -> bool {
+    dx != 0
+        && dy != 0
+        && map[(x + dx) as usize][y as usize].blocked
+        && map[x as usize][(y + dy) as usize].blocked
+}
+
 fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
-    // check if tile is blocked
-    if map[x as usize][y as usize].blocked {
+    // check if tile is blocked, or no place to put anything
+    if !map[x as usize][y as usize].walkable() {
         return true;
     }
 
@@ -353,7 +1320,7 @@ fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
         
 }
 
-fn player_move_or_attack(dx: i32, dy: i32, game: &Game, objects: &mut [Object]) { 
+fn player_move_or_attack(dx: i32, dy: i32, game: &mut Game, objects: &mut Vec<Object>) { 
     // the coordinates the player is moving to / attacking 
     let x = objects[PLAYER].x + dx ; 
     let y = objects[PLAYER].y + dy ;
@@ -366,57 +1333,434 @@ fn player_move_or_attack(dx: i32, dy: i32, game: &Game, objects: &mut [Object])
     match target_id {
         Some(target_id) => {
             let (player,target) = mut_two(PLAYER, target_id, objects);
-            player.attack(target);
+            player.attack(target, game);
+        }
+        // bumping into a door opens it
+        None if game.map[x as usize][y as usize].door.is_some_and(|door| door != Door::Open) => {
+            player_open_door(x, y, game);
         }
         None => { 
             move_by(PLAYER, dx, dy, &game.map, objects);
+            if objects[PLAYER].pos() == (x, y) {
+                player_step_on(game, objects);
+            }
         }
     }
 }
 
+/// What the ground does to the player who just stepped on it
+fn player_step_on(game: &mut Game, objects: &mut Vec<Object>) {
+    let (x, y) = objects[PLAYER].pos();
+    match game.map[x as usize][y as usize].terrain {
+        Terrain::Rubble => objects[PLAYER].slowed = true,
+        Terrain::Lava => {
+            game.messages.add("The lava burns you!", ORANGE);
+            objects[PLAYER].take_damage(LAVA_DAMAGE, game);
+        }
+        Terrain::Chasm => {
+            game.messages.add("You fall into the chasm!", RED);
+            objects[PLAYER].take_damage(FALL_DAMAGE, game);
+            if objects[PLAYER].alive {
+                descend(game, objects);
+            }
+        }
+        Terrain::Floor | Terrain::Wall | Terrain::DeepWater => {}
+    }
+}
+
+
+/// Everything needed to spawn one kind of monster, as read from the monsters
+/// file. Unknown keys are errors, or a misspelled optional one would be ignored
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MonsterTemplate {
+    name: String,
+    glyph: char,
+    color: Color,
+    max_hp: i32,
+    defence: i32,
+    power: i32,
+    xp: i32,
+    vision_radius: i32,
+    ai: MonsterAi,
+    spawn_weight: Vec<Transition>,
+    min_depth: u32,
+    #[serde(default)]
+    swims: bool,
+    #[serde(default)]
+    light: Option<Light>,
+}
+
+/// Which state a monster of some kind spawns in
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MonsterAi {
+    Sleeping,
+    Wandering,
+    /// asleep or wandering, rolled at spawn
+    Random,
+}
+
+impl MonsterAi {
+    fn initial(self, rng: &mut GameRng) -> Ai {
+        match self {
+            MonsterAi::Sleeping => Ai::Sleeping,
+            MonsterAi::Wandering => Ai::Wandering,
+            MonsterAi::Random if rng.gen_bool(SLEEPING_CHANCE) => Ai::Sleeping,
+            MonsterAi::Random => Ai::Wandering,
+        }
+    }
+}
+
+impl MonsterTemplate {
+    /// everything that would make a monster broken or unspawnable
+    fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("name must not be empty".into());
+        }
+        if self.name == "player" {
+            // equipment bonuses go to whatever object is named "player"
+            return Err("name \"player\" is reserved".into());
+        }
+        if self.max_hp <= 0 {
+            return Err(format!("max_hp must be positive, got {}", self.max_hp));
+        }
+        let not_negative = [
+            ("defence", self.defence),
+            ("power", self.power),
+            ("xp", self.xp),
+            ("vision_radius", self.vision_radius),
+        ];
+        for &(field, value) in not_negative.iter() {
+            if value < 0 {
+                return Err(format!("{} must not be negative, got {}", field, value));
+            }
+        }
+        if self.spawn_weight.is_empty() {
+            return Err("spawn_weight must have at least one entry".into());
+        }
+        let sorted = self
+            .spawn_weight
+            .windows(2)
+            .all(|pair| pair[0].level < pair[1].level);
+        if !sorted {
+            return Err("spawn_weight levels must be in increasing order".into());
+        }
+        if self.spawn_weight.iter().all(|transition| transition.value == 0) {
+            return Err("spawn_weight must be positive at some depth".into());
+        }
+        if self.min_depth == 0 {
+            return Err("min_depth must be at least 1, got 0".into());
+        }
+        if let Some(light) = self.light {
+            if light.radius <= 0 {
+                return Err(format!("light radius must be positive, got {}", light.radius));
+            }
+        }
+        Ok(())
+    }
+
+    fn spawn(&self, x: i32, y: i32, rng: &mut GameRng) -> Object {
+        let mut monster = Object::new(x, y, self.glyph, &self.name, self.color, true);
+        monster.fighter = Some(Fighter {
+            base_max_hp: self.max_hp,
+            hp: self.max_hp,
+            base_defence: self.defence,
+            base_power: self.power,
+            xp: self.xp,
+            on_death: DeathCallback::Monster,
+        });
+        monster.ai = Some(self.ai.initial(rng));
+        monster.vision_radius = self.vision_radius;
+        monster.swims = self.swims;
+        monster.light = self.light;
+        monster.alive = true;
+        monster
+    }
+}
+
+/// Layout of the monsters file: a list of `[[monster]]` tables
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MonstersFile {
+    monster: Vec<MonsterTemplate>,
+}
+
+/// Read and check the monster templates, naming the file and the offending
+/// monster in every error
+fn load_monsters(path: &str) -> Result<Vec<MonsterTemplate>, Box<dyn Error>> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+    let MonstersFile { monster: monsters } =
+        toml::from_str(&contents).map_err(|e| format!("{}: {}", path, e))?;
+
+    if monsters.is_empty() {
+        return Err(format!("{}: no monsters defined", path).into());
+    }
+    for (index, monster) in monsters.iter().enumerate() {
+        monster.validate().map_err(|e| {
+            format!("{}: monster #{} ({:?}): {}", path, index + 1, monster.name, e)
+        })?;
+    }
+    Ok(monsters)
+}
+
+/// A value that kicks in from a given dungeon level onwards
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Transition {
+    level: u32,
+    value: u32,
+}
+
+/// Returns the value that applies at the given level. The table must be
+/// sorted by level
+fn from_dungeon_level(table: &[Transition], level: u32) -> u32 {
+    table
+        .iter()
+        .rev()
+        .find(|transition| level >= transition.level)
+        .map_or(0, |transition| transition.value)
+}
+
+fn place_objects(
+    room: Rect,
+    map: &Map,
+    objects: &mut Vec<Object>,
+    monsters: &[MonsterTemplate],
+    level: u32,
+    rng: &mut GameRng,
+) {
+    // maximum number of monsters per room, deeper levels are more crowded
+    let max_monsters = from_dungeon_level(
+        &[
+            Transition { level: 1, value: 3 },
+            Transition { level: 4, value: 4 },
+            Transition { level: 6, value: 5 },
+        ],
+        level,
+    );
+
+    // only monsters deep enough can show up, picked by their spawn weight at this depth
+    let candidates: Vec<&MonsterTemplate> = monsters
+        .iter()
+        .filter(|monster| level >= monster.min_depth)
+        .collect();
+    let monster_choice = WeightedIndex::new(
+        candidates
+            .iter()
+            .map(|monster| from_dungeon_level(&monster.spawn_weight, level)),
+    )
+    .ok();
+
+    // choosing random number of monsters
+    let num_monsters = rng.gen_range(0, max_monsters + 1);
 
-fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>) {
-    // choosing random number of monsters
-    let num_monsters = rand::thread_rng().gen_range(0, MAX_ROOM_MONSTERS + 1);
-
     for _ in 0..num_monsters {
         // choosing a random spot for the monstor
-        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
-        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+
+        if !is_blocked(x, y, map, objects) {
+            if let Some(ref monster_choice) = monster_choice {
+                let template = candidates[monster_choice.sample(rng)];
+                objects.push(template.spawn(x, y, rng));
+            }
+        }
+    }
+
+    // item odds: potions are common, better equipment shows up deeper
+    let item_chances = [
+        (Item::Heal, 35),
+        (Item::Sword, from_dungeon_level(&[Transition { level: 4, value: 5 }], level)),
+        (Item::Shield, from_dungeon_level(&[Transition { level: 8, value: 15 }], level)),
+        (Item::Armor, from_dungeon_level(&[Transition { level: 6, value: 10 }], level)),
+    ];
+    let item_choice = WeightedIndex::new(item_chances.iter().map(|&(_, weight)| weight)).unwrap();
 
+    // choosing random number of items
+    let num_items = rng.gen_range(0, MAX_ROOM_ITEMS + 1);
+
+    for _ in 0..num_items {
+        // choosing a random spot for the item
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+
+        // only place it if the tile is not blocked
         if !is_blocked(x, y, map, objects) {
+            let item = make_item(item_chances[item_choice.sample(rng)].0, x, y);
+            objects.push(item);
+        }
+    }
+}
 
-            let mut monster = if rand::random::<f32>() < 0.6 {
-                // 60% change of getting an orc
-                // creating an orc
-                let mut orc = Object::new(x, y, 'o', "orc", DESATURATED_GREEN, true);
-                orc.fighter = Some(Fighter {
-                    max_hp: 10,
-                    hp: 10,
-                    defence: 0, 
-                    power: 3,
-                    on_death: DeathCallback::Monster,
-                });
-                orc.ai = Some(Ai::Basic);
-                orc
-            } else {
-                let mut troll = Object::new(x, y, 'T', "troll", DARKER_GREEN, true);
-                
-                troll.fighter = Some( Fighter { 
-                    max_hp: 16, 
-                    hp: 16,
-                    defence: 1,
-                    power: 4,
-                    on_death: DeathCallback::Monster,
-                });
-                troll.ai = Some(Ai::Basic);
-                troll
-            };
-            
-            monster.alive = true;
-            objects.push(monster);
+fn make_item(kind: Item, x: i32, y: i32) -> Object {
+    let mut object = match kind {
+        Item::Heal => {
+            // create a healing potion
+            Object::new(x, y, '!', "healing potion", VIOLET, false)
+        }
+        Item::Sword => {
+            let mut object = Object::new(x, y, '/', "sword", SKY, false);
+            object.equipment = Some(Equipment {
+                slot: Slot::MainHand,
+                equipped: false,
+                max_hp_bonus: 0,
+                defence_bonus: 0,
+                power_bonus: 3,
+            });
+            object
+        }
+        Item::Shield => {
+            let mut object = Object::new(x, y, '[', "shield", DARKER_ORANGE, false);
+            object.equipment = Some(Equipment {
+                slot: Slot::OffHand,
+                equipped: false,
+                max_hp_bonus: 0,
+                defence_bonus: 1,
+                power_bonus: 0,
+            });
+            object
+        }
+        Item::Armor => {
+            let mut object = Object::new(x, y, ']', "chain mail", LIGHT_GREY, false);
+            object.equipment = Some(Equipment {
+                slot: Slot::Body,
+                equipped: false,
+                max_hp_bonus: 10,
+                defence_bonus: 1,
+                power_bonus: 0,
+            });
+            object
+        }
+        // opens the locked door of its level
+        Item::Key => Object::new(x, y, '-', "key", GOLD, false),
+    };
+    object.item = Some(kind);
+    object
+}
+
+/// add to the player's inventory and remove from the map
+fn pick_up_item(object_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
+    if game.inventory.len() >= INVENTORY_SIZE {
+        game.messages.add(
+            format!(
+                "Your inventory is full, cannot pick up {}.",
+                objects[object_id].name
+            ),
+            RED,
+        );
+    } else {
+        let item = objects.swap_remove(object_id);
+        game.messages
+            .add(format!("You picked up a {}!", item.name), GREEN);
+        let index = game.inventory.len();
+        let slot = item.equipment.map(|e| e.slot);
+        game.inventory.push(item);
+
+        // automatically equip, if the corresponding equipment slot is unused
+        if let Some(slot) = slot {
+            if get_equipped_in_slot(slot, &game.inventory).is_none() {
+                game.inventory[index].equip(&mut game.messages);
+            }
+        }
+    }
+}
+
+/// inventory index of the item equipped in the given slot, if any
+fn get_equipped_in_slot(slot: Slot, inventory: &[Object]) -> Option<usize> {
+    inventory.iter().position(|item| {
+        item.equipment
+            .is_some_and(|e| e.equipped && e.slot == slot)
+    })
+}
+
+/// put an inventory item back on the map, under the player
+fn drop_item(inventory_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
+    let mut item = game.inventory.remove(inventory_id);
+    if item.equipment.is_some() {
+        item.unequip(&mut game.messages);
+    }
+    item.set_pos(objects[PLAYER].x, objects[PLAYER].y);
+    game.messages
+        .add(format!("You dropped a {}.", item.name), YELLOW);
+    objects.push(item);
+    clamp_player_hp(game, objects);
+}
+
+/// keep the player's HP within a max_hp that unequipping may have lowered
+fn clamp_player_hp(game: &Game, objects: &mut [Object]) {
+    let max_hp = objects[PLAYER].max_hp(game);
+    if let Some(ref mut fighter) = objects[PLAYER].fighter {
+        fighter.hp = cmp::min(fighter.hp, max_hp);
+    }
+}
+
+fn use_item(inventory_id: usize, game: &mut Game, objects: &mut [Object]) {
+    use Item::*;
+    // just call the "use_function" if it is defined
+    if let Some(item) = game.inventory[inventory_id].item {
+        let on_use: fn(usize, &mut Game, &mut [Object]) -> UseResult = match item {
+            Heal => cast_heal,
+            Sword | Shield | Armor => toggle_equipment,
+            Key => use_key,
+        };
+        match on_use(inventory_id, game, objects) {
+            UseResult::UsedUp => {
+                // destroy after use, unless it was cancelled for some reason
+                game.inventory.remove(inventory_id);
+            }
+            UseResult::UsedAndKept => {} // do nothing
+            UseResult::Cancelled => {
+                game.messages.add("Cancelled", WHITE);
+            }
+        }
+    } else {
+        game.messages.add(
+            format!("The {} cannot be used.", game.inventory[inventory_id].name),
+            WHITE,
+        );
+    }
+}
+
+fn cast_heal(_inventory_id: usize, game: &mut Game, objects: &mut [Object]) -> UseResult {
+    // heal the player
+    let player = &mut objects[PLAYER];
+    if let Some(fighter) = player.fighter {
+        if fighter.hp >= player.max_hp(game) {
+            game.messages.add("You are already at full health.", RED);
+            return UseResult::Cancelled;
+        }
+        game.messages
+            .add("Your wounds start to feel better!", LIGHT_VIOLET);
+        player.heal(HEAL_AMOUNT, game);
+        return UseResult::UsedUp;
+    }
+    UseResult::Cancelled
+}
+
+fn use_key(_inventory_id: usize, game: &mut Game, _objects: &mut [Object]) -> UseResult {
+    game.messages
+        .add("Walk into a locked door to unlock it.", WHITE);
+    UseResult::UsedAndKept
+}
+
+fn toggle_equipment(inventory_id: usize, game: &mut Game, objects: &mut [Object]) -> UseResult {
+    let equipment = match game.inventory[inventory_id].equipment {
+        Some(equipment) => equipment,
+        None => return UseResult::Cancelled,
+    };
+    if equipment.equipped {
+        game.inventory[inventory_id].unequip(&mut game.messages);
+    } else {
+        // if the slot is already being used, unequip whatever is there first
+        if let Some(current) = get_equipped_in_slot(equipment.slot, &game.inventory) {
+            game.inventory[current].unequip(&mut game.messages);
         }
+        game.inventory[inventory_id].equip(&mut game.messages);
     }
+    clamp_player_hp(game, objects);
+    UseResult::UsedAndKept
 }
 /*
     To avoid ownership issues, we splice the items into two slices
@@ -427,115 +1771,1087 @@ fn mut_two<T>(index1: usize, index2: usize, items: &mut[T]) -> (&mut T, &mut T)
     let split_at_index = cmp::max(index1, index2);
     let(first_slice, second_slice) = items.split_at_mut(split_at_index);
 
-   if index1 < index2 {
-       (&mut first_slice[index1], &mut second_slice[0])
-   } else {
-       (&mut second_slice[0], &mut first_slice[index2])
-   }
+   if index1 < index2 {
+       (&mut first_slice[index1], &mut second_slice[0])
+   } else {
+       (&mut second_slice[0], &mut first_slice[index2])
+   }
+}
+
+/// The ways a level can be laid out. Every generator carves rooms into a
+/// map full of walls and reports them: the player starts in the first one
+/// and the stairs go in the last one
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum MapGenerator {
+    /// rooms dropped at random, each linked to the previous one
+    Rooms,
+    /// binary space partition: one room per leaf, siblings linked together
+    Bsp,
+    /// cellular automata caves, reported as a grid of open regions
+    Caves,
+}
+
+impl MapGenerator {
+    /// pick the generator for a new level: built rooms near the surface,
+    /// more and more caves deeper down
+    fn for_level(level: u32, rng: &mut GameRng) -> Self {
+        let rooms = from_dungeon_level(
+            &[
+                Transition { level: 1, value: 50 },
+                Transition { level: 4, value: 30 },
+            ],
+            level,
+        );
+        let bsp = from_dungeon_level(
+            &[
+                Transition { level: 1, value: 50 },
+                Transition { level: 4, value: 40 },
+            ],
+            level,
+        );
+        let caves = from_dungeon_level(
+            &[
+                Transition { level: 2, value: 15 },
+                Transition { level: 4, value: 30 },
+                Transition { level: 6, value: 50 },
+            ],
+            level,
+        );
+        let generator_chances = [
+            (MapGenerator::Rooms, rooms),
+            (MapGenerator::Bsp, bsp),
+            (MapGenerator::Caves, caves),
+        ];
+        let generator_choice =
+            WeightedIndex::new(generator_chances.iter().map(|&(_, weight)| weight)).unwrap();
+        generator_chances[generator_choice.sample(rng)].0
+    }
+
+    /// whether the generator builds walled rooms, which can have doors
+    fn makes_rooms(self) -> bool {
+        self != MapGenerator::Caves
+    }
+
+    fn generate(self, map: &mut Map, rng: &mut GameRng) -> Vec<Rect> {
+        use MapGenerator::*;
+        let generate: fn(&mut Map, &mut GameRng) -> Vec<Rect> = match self {
+            Rooms => make_random_rooms,
+            Bsp => make_bsp_rooms,
+            Caves => make_caves,
+        };
+        generate(map, rng)
+    }
+}
+
+/// Knobs for the post-processing done on every generated map
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct GeneratorParams {
+    /// chance that two nearby rooms far apart on foot get an extra corridor
+    loop_chance: f64,
+    /// only rooms at most this far apart (as the crow flies) are looped
+    loop_max_distance: f32,
+    /// a loop is only worth it if walking is this many times longer
+    loop_min_detour: f32,
+    /// fill in corridors that lead nowhere
+    prune_dead_ends: bool,
+    /// use this generator on every level, instead of picking one by depth
+    generator: Option<MapGenerator>,
+}
+
+impl FromStr for MapGenerator {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "rooms" => Ok(MapGenerator::Rooms),
+            "bsp" => Ok(MapGenerator::Bsp),
+            "caves" => Ok(MapGenerator::Caves),
+            _ => Err(format!("unknown map generator {:?}", name)),
+        }
+    }
+}
+
+impl Default for GeneratorParams {
+    fn default() -> Self {
+        GeneratorParams {
+            loop_chance: 0.5,
+            loop_max_distance: 20.0,
+            loop_min_detour: 2.0,
+            prune_dead_ends: true,
+            generator: None,
+        }
+    }
+}
+
+fn make_map(
+    objects: &mut Vec<Object>,
+    monsters: &[MonsterTemplate],
+    params: GeneratorParams,
+    level: u32,
+    rng: &mut GameRng,
+) -> Map {
+    // the player is the first object, remove everything from the last level
+    objects.truncate(PLAYER + 1);
+
+    let (mut map, rooms, generator) = loop {
+        // blocked tiles filled
+        let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+        let generator = params
+            .generator
+            .unwrap_or_else(|| MapGenerator::for_level(level, rng));
+        let rooms = generator.generate(&mut map, rng);
+        add_loops(&rooms, &mut map, params, rng);
+        if params.prune_dead_ends {
+            prune_dead_ends(&rooms, &mut map);
+        }
+
+        // every open tile must be reachable from the start, or the map is dug again
+        let start = rooms.first().and_then(|&room| open_spot(room, &map));
+        if let Some(start) = start {
+            if connect_regions(start, &mut map, rng) {
+                break (map, rooms, generator);
+            }
+        }
+    };
+
+    place_terrain(&rooms, &mut map, level, rng);
+
+    // the player starts from the center of the first room
+    let (start_x, start_y) = open_spot(rooms[0], &map).expect("generated rooms are open");
+    objects[PLAYER].set_pos(start_x, start_y);
+
+    // adding characters to the rooms
+    for &room in &rooms {
+        place_objects(room, &map, objects, monsters, level, rng);
+    }
+
+    // create stairs at the center of the last room
+    let (last_room_x, last_room_y) =
+        open_spot(rooms[rooms.len() - 1], &map).expect("generated rooms are open");
+    let mut stairs = Object::new(last_room_x, last_room_y, '>', "stairs", WHITE, false);
+    stairs.always_visible = true;
+    objects.push(stairs);
+
+    if generator.makes_rooms() {
+        place_doors(&rooms, &mut map, objects, rng);
+    }
+    light_rooms(&rooms, &mut map, objects, level, rng);
+
+    map
+}
+
+/// Light up some of the rooms, fewer the deeper it goes, and leave a
+/// brazier in some of the dark ones
+fn light_rooms(rooms: &[Rect], map: &mut Map, objects: &mut Vec<Object>, level: u32, rng: &mut GameRng) {
+    let lit_chance = from_dungeon_level(
+        &[
+            Transition { level: 1, value: 70 },
+            Transition { level: 3, value: 40 },
+            Transition { level: 6, value: 15 },
+        ],
+        level,
+    );
+
+    for room in rooms {
+        if rng.gen_range(0, 100) < lit_chance {
+            // the walls too, so the room shows up whole
+            for x in room.x1..=room.x2 {
+                for y in room.y1..=room.y2 {
+                    map[x as usize][y as usize].lit = true;
+                }
+            }
+        } else if rng.gen_bool(BRAZIER_CHANCE) {
+            let x = rng.gen_range(room.x1 + 1, room.x2);
+            let y = rng.gen_range(room.y1 + 1, room.y2);
+            if !is_blocked(x, y, map, objects) && !objects.iter().any(|object| object.pos() == (x, y)) {
+                let mut brazier = Object::new(x, y, '&', "brazier", ORANGE, false);
+                brazier.light = Some(BRAZIER_LIGHT);
+                objects.push(brazier);
+            }
+        }
+    }
+}
+
+/// The open tile closest to the center of a room, if it has any. Only looks
+/// where `place_objects` would, inside the room's walls
+fn open_spot(room: Rect, map: &Map) -> Option<(i32, i32)> {
+    let (center_x, center_y) = room.center();
+    let mut spots = vec![];
+    for x in (room.x1 + 1)..room.x2 {
+        for y in (room.y1 + 1)..room.y2 {
+            if map[x as usize][y as usize].walkable() {
+                spots.push((x, y));
+            }
+        }
+    }
+    spots
+        .into_iter()
+        .min_by_key(|&(x, y)| (x - center_x).pow(2) + (y - center_y).pow(2))
+}
+
+/// All the tiles reachable from `start`, walking in eight directions and
+/// opening doors on the way. Locked doors only open `with_key`
+fn flood_fill(start: (i32, i32), map: &Map, with_key: bool) -> Vec<(i32, i32)> {
+    let distances = walking_distances(start, map, with_key);
+    let mut region = vec![];
+    for x in 0..MAP_WIDTH {
+        for y in 0..MAP_HEIGHT {
+            if distances[x as usize][y as usize].is_some() {
+                region.push((x, y));
+            }
+        }
+    }
+    region
+}
+
+/// Every separate open area of the map, each as the list of its tiles
+fn open_regions(map: &Map) -> Vec<Vec<(i32, i32)>> {
+    let mut in_region = vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    let mut regions = vec![];
+    for x in 0..MAP_WIDTH {
+        for y in 0..MAP_HEIGHT {
+            if in_region[x as usize][y as usize] || !map[x as usize][y as usize].passable(true) {
+                continue;
+            }
+            let region = flood_fill((x, y), map, true);
+            for &(rx, ry) in &region {
+                in_region[rx as usize][ry as usize] = true;
+            }
+            regions.push(region);
+        }
+    }
+    regions
+}
+
+/// Pour pools of water, lava, chasms and rubble into some of the rooms.
+/// A feature that would cut part of the level off is taken out again
+fn place_terrain(rooms: &[Rect], map: &mut Map, level: u32, rng: &mut GameRng) {
+    // hazards show up deeper
+    let terrain_chances = [
+        (Terrain::Rubble, 40),
+        (Terrain::DeepWater, 30),
+        (Terrain::Chasm, from_dungeon_level(&[Transition { level: 2, value: 15 }], level)),
+        (Terrain::Lava, from_dungeon_level(&[Transition { level: 3, value: 20 }], level)),
+    ];
+    let terrain_choice = WeightedIndex::new(terrain_chances.iter().map(|&(_, weight)| weight)).unwrap();
+
+    for room in rooms {
+        if !rng.gen_bool(TERRAIN_FEATURE_CHANCE) || room.x2 - room.x1 < 3 || room.y2 - room.y1 < 3 {
+            continue;
+        }
+        let terrain = terrain_chances[terrain_choice.sample(rng)].0;
+        let (center_x, center_y) = (
+            rng.gen_range(room.x1 + 1, room.x2),
+            rng.gen_range(room.y1 + 1, room.y2),
+        );
+        let radius = rng.gen_range(1, TERRAIN_FEATURE_MAX_RADIUS + 1);
+
+        // a rough blob of the terrain, inside the room's walls
+        let mut changed = vec![];
+        for x in (room.x1 + 1).max(center_x - radius)..room.x2.min(center_x + radius + 1) {
+            for y in (room.y1 + 1).max(center_y - radius)..room.y2.min(center_y + radius + 1) {
+                let tile = map[x as usize][y as usize];
+                let inside = (x - center_x).pow(2) + (y - center_y).pow(2) <= radius.pow(2);
+                if inside && tile.terrain == Terrain::Floor && tile.door.is_none() {
+                    changed.push((x, y, tile));
+                    map[x as usize][y as usize] = Tile::new(terrain);
+                }
+            }
+        }
+
+        if !terrain_keeps_level_whole(rooms, map) {
+            for (x, y, tile) in changed {
+                map[x as usize][y as usize] = tile;
+            }
+        }
+    }
+}
+
+/// whether the first and last rooms still have somewhere to stand, and
+/// every walkable tile can be reached from the start
+fn terrain_keeps_level_whole(rooms: &[Rect], map: &Map) -> bool {
+    let start = open_spot(rooms[0], map);
+    let end = open_spot(rooms[rooms.len() - 1], map);
+    match (start, end) {
+        (Some(start), Some(_)) => {
+            let walkable = map.iter().flatten().filter(|tile| tile.passable(true)).count();
+            flood_fill(start, map, true).len() == walkable
+        }
+        _ => false,
+    }
+}
+
+/// Put doors in the doorways of room walls: single open tiles in a wall,
+/// with the room on one side and a corridor on the other. Maybe lock one of
+/// them, leaving its key somewhere the player can reach without it
+fn place_doors(rooms: &[Rect], map: &mut Map, objects: &mut Vec<Object>, rng: &mut GameRng) {
+    let passable = |map: &Map, x: i32, y: i32| {
+        x >= 0 && y >= 0 && x < MAP_WIDTH && y < MAP_HEIGHT && map[x as usize][y as usize].passable(true)
+    };
+
+    let mut doors = vec![];
+    for room in rooms {
+        let mut wall = vec![];
+        for x in room.x1..=room.x2 {
+            wall.push((x, room.y1, false));
+            wall.push((x, room.y2, false));
+        }
+        for y in room.y1..=room.y2 {
+            wall.push((room.x1, y, true));
+            wall.push((room.x2, y, true));
+        }
+
+        for (x, y, vertical_wall) in wall {
+            if !passable(map, x, y) || map[x as usize][y as usize].door.is_some() {
+                continue;
+            }
+            // open across the wall, solid along it
+            let (across, along) = if vertical_wall {
+                ((1, 0), (0, 1))
+            } else {
+                ((0, 1), (1, 0))
+            };
+            let doorway = passable(map, x - across.0, y - across.1)
+                && passable(map, x + across.0, y + across.1)
+                && !passable(map, x - along.0, y - along.1)
+                && !passable(map, x + along.0, y + along.1);
+            if doorway && !objects.iter().any(|object| object.pos() == (x, y)) && rng.gen_bool(DOOR_CHANCE) {
+                map[x as usize][y as usize] = Tile::door(Door::Closed);
+                doors.push((x, y));
+            }
+        }
+    }
+
+    if doors.is_empty() || !rng.gen_bool(LOCKED_DOOR_CHANCE) {
+        return;
+    }
+    let (locked_x, locked_y) = doors[rng.gen_range(0, doors.len())];
+    map[locked_x as usize][locked_y as usize] = Tile::door(Door::Locked);
+
+    // the key goes on a free floor tile on the player's side of the door
+    let key_spots: Vec<(i32, i32)> = flood_fill(objects[PLAYER].pos(), map, false)
+        .into_iter()
+        .filter(|&(x, y)| {
+            map[x as usize][y as usize].door.is_none()
+                && !objects.iter().any(|object| object.pos() == (x, y))
+        })
+        .collect();
+    if key_spots.is_empty() {
+        // nowhere to put the key: leave the door unlocked
+        map[locked_x as usize][locked_y as usize] = Tile::door(Door::Closed);
+        return;
+    }
+    let (key_x, key_y) = key_spots[rng.gen_range(0, key_spots.len())];
+    objects.push(make_item(Item::Key, key_x, key_y));
+}
+
+/// Flood-fill from the start and tunnel from every part of the map that can't
+/// be reached to the closest part that can. Returns false if the map is
+/// still in pieces after `MAX_MAP_REPAIRS` tunnels
+fn connect_regions(start: (i32, i32), map: &mut Map, rng: &mut GameRng) -> bool {
+    for _ in 0..MAX_MAP_REPAIRS {
+        let reachable = flood_fill(start, map, true);
+        let mut is_reachable = vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+        for &(x, y) in &reachable {
+            is_reachable[x as usize][y as usize] = true;
+        }
+
+        let cut_off = open_regions(map)
+            .into_iter()
+            .find(|region| !is_reachable[region[0].0 as usize][region[0].1 as usize]);
+        let cut_off = match cut_off {
+            Some(cut_off) => cut_off,
+            None => return true, // all in one piece
+        };
+
+        // dig the shortest tunnel between the two
+        let (from, to) = cut_off
+            .iter()
+            .flat_map(|&from| reachable.iter().map(move |&to| (from, to)))
+            .min_by_key(|&((from_x, from_y), (to_x, to_y))| {
+                (from_x - to_x).pow(2) + (from_y - to_y).pow(2)
+            })
+            .expect("both regions have tiles");
+        connect_points(from, to, map, rng);
+    }
+    false
+}
+
+/// Walking distance from `start` to every tile, `None` where it can't be
+/// reached. Steps go in eight directions, opening doors on the way; locked
+/// doors only open `with_key`
+fn walking_distances(start: (i32, i32), map: &Map, with_key: bool) -> Vec<Vec<Option<u32>>> {
+    let mut distances = vec![vec![None; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    if !map[start.0 as usize][start.1 as usize].passable(with_key) {
+        return distances;
+    }
+    let mut queue = VecDeque::new();
+    distances[start.0 as usize][start.1 as usize] = Some(0);
+    queue.push_back(start);
+
+    while let Some((x, y)) = queue.pop_front() {
+        let distance = distances[x as usize][y as usize].unwrap_or(0);
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || ny < 0 || nx >= MAP_WIDTH || ny >= MAP_HEIGHT {
+                    continue;
+                }
+                if distances[nx as usize][ny as usize].is_some()
+                    || !map[nx as usize][ny as usize].passable(with_key)
+                    || squeezes_between_walls(x, y, dx, dy, map)
+                {
+                    continue;
+                }
+                distances[nx as usize][ny as usize] = Some(distance + 1);
+                queue.push_back((nx, ny));
+            }
+        }
+    }
+    distances
+}
+
+/// Add corridors between rooms that are close together but a long walk
+/// apart, so there is more than one way around the level
+fn add_loops(rooms: &[Rect], map: &mut Map, params: GeneratorParams, rng: &mut GameRng) {
+    let spots: Vec<(i32, i32)> = rooms.iter().filter_map(|&room| open_spot(room, map)).collect();
+    for (i, &from) in spots.iter().enumerate() {
+        // recomputed for every room, so new loops count for the next ones
+        let distances = walking_distances(from, map, true);
+        for &to in &spots[(i + 1)..] {
+            let straight = (((to.0 - from.0).pow(2) + (to.1 - from.1).pow(2)) as f32).sqrt();
+            if straight > params.loop_max_distance {
+                continue;
+            }
+            let walking = distances[to.0 as usize][to.1 as usize];
+            let long_way_round =
+                walking.is_none_or(|walking| walking as f32 >= straight * params.loop_min_detour);
+            if long_way_round && rng.gen_bool(params.loop_chance) {
+                connect_points(from, to, map, rng);
+            }
+        }
+    }
+}
+
+/// Fill in corridor tiles that lead nowhere, until none are left. Room
+/// tiles are never touched
+fn prune_dead_ends(rooms: &[Rect], map: &mut Map) {
+    let in_room = |x: i32, y: i32| {
+        rooms
+            .iter()
+            .any(|room| x > room.x1 && x < room.x2 && y > room.y1 && y < room.y2)
+    };
+    let mut pruned = true;
+    while pruned {
+        pruned = false;
+        for x in 1..(MAP_WIDTH - 1) {
+            for y in 1..(MAP_HEIGHT - 1) {
+                if map[x as usize][y as usize].blocked || in_room(x, y) {
+                    continue;
+                }
+                // a dead end has a single way out
+                let exits = [(0, -1), (0, 1), (-1, 0), (1, 0)]
+                    .iter()
+                    .filter(|&&(dx, dy)| !map[(x + dx) as usize][(y + dy) as usize].blocked)
+                    .count();
+                if exits <= 1 {
+                    map[x as usize][y as usize] = Tile::wall();
+                    pruned = true;
+                }
+            }
+        }
+    }
+}
+
+/// Check that a generated level is one connected space: every open tile,
+/// the stairs included, can be reached from where the player stands, and
+/// the key to a locked door can be reached without it
+fn check_map(map: &Map, objects: &[Object]) -> Result<(), String> {
+    let start = objects[PLAYER].pos();
+    let reachable = flood_fill(start, map, true).len();
+    let open = map.iter().flatten().filter(|tile| tile.passable(true)).count();
+    if reachable != open {
+        return Err(format!(
+            "{} of {} open tiles cannot be reached from the start",
+            open - reachable,
+            open
+        ));
+    }
+
+    let has_locked_door = map.iter().flatten().any(|tile| tile.door == Some(Door::Locked));
+    if has_locked_door {
+        let without_key = flood_fill(start, map, false);
+        let key_reachable = objects
+            .iter()
+            .any(|object| object.item == Some(Item::Key) && without_key.contains(&object.pos()));
+        if !key_reachable {
+            return Err("the key is locked behind its own door".into());
+        }
+    }
+    Ok(())
+}
+
+/// Generate the first few levels for every seed below `count` and check
+/// each map. Needs no window; returns how many maps were broken
+fn check_maps(count: u64, monsters: &[MonsterTemplate], generator_params: GeneratorParams) -> u64 {
+    let mut failures = 0;
+    for seed in 0..count {
+        let (mut game, mut objects) = new_game(seed, monsters.to_vec(), generator_params);
+        loop {
+            if let Err(e) = check_map(&game.map, &objects) {
+                eprintln!("seed {}, level {}: {}", seed, game.dungeon_level, e);
+                failures += 1;
+            }
+            if game.dungeon_level >= CHECK_MAPS_DEPTH {
+                break;
+            }
+            next_level(&mut game, &mut objects);
+        }
+    }
+    failures
+}
+
+/// Link two points with an L-shaped tunnel, bending one way or the other at random
+fn connect_points(from: (i32, i32), to: (i32, i32), map: &mut Map, rng: &mut GameRng) {
+    let ((prev_x, prev_y), (new_x, new_y)) = (from, to);
+    if rng.gen() {
+        // move horizontally and then vertically
+        create_h_tunnel(prev_x, new_x, prev_y, map);
+        create_v_tunnel(prev_y, new_y, new_x, map);
+    } else {
+        create_v_tunnel(prev_y, new_y, prev_x, map);
+        create_h_tunnel(prev_x, new_x, new_y, map);
+    }
+}
+
+fn make_random_rooms(map: &mut Map, rng: &mut GameRng) -> Vec<Rect> {
+    let mut rooms: Vec<Rect> = vec![];
+
+    for _ in 0..MAX_ROOMS {
+        // random width and height
+        let w = rng.gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+        let h = rng.gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+
+        // random position without going out of the boundaries of the map
+        let x = rng.gen_range(0, MAP_WIDTH - w);
+        let y = rng.gen_range(0, MAP_HEIGHT - h);
+
+        let new_room = Rect::new(x, y, w, h);
+
+        // Checking intersection with other rooms
+        let failed = rooms
+            .iter()
+            .any(|other_room| new_room.intersects_with(other_room));
+
+        if !failed {
+            // going ahead and creating the room
+            create_room(new_room, map);
+
+            // connect all the other rooms with a tunnel to the previous one
+            if let Some(prev_room) = rooms.last() {
+                connect_points(prev_room.center(), new_room.center(), map, rng);
+            }
+
+            rooms.push(new_room);
+        }
+    }
+
+    rooms
+}
+
+fn make_caves(map: &mut Map, rng: &mut GameRng) -> Vec<Rect> {
+    let min_open_tiles = (MAP_WIDTH * MAP_HEIGHT) as f32 * CAVE_MIN_OPEN_FRACTION;
+    loop {
+        // random noise, keeping the outer edge of the map solid
+        for x in 1..(MAP_WIDTH - 1) {
+            for y in 1..(MAP_HEIGHT - 1) {
+                map[x as usize][y as usize] = if rng.gen_bool(CAVE_WALL_CHANCE) {
+                    Tile::wall()
+                } else {
+                    Tile::empty()
+                };
+            }
+        }
+
+        for _ in 0..CAVE_SMOOTHING_STEPS {
+            smooth_caves(map);
+        }
+
+        // fill in every pocket that can't be reached from the biggest cave
+        let mut regions = open_regions(map);
+        regions.sort_by_key(|region| region.len());
+        let largest = regions.pop().unwrap_or_default();
+        for region in regions {
+            for (x, y) in region {
+                map[x as usize][y as usize] = Tile::wall();
+            }
+        }
+
+        // too cramped to be fun: dig again
+        if largest.len() as f32 >= min_open_tiles {
+            break;
+        }
+    }
+
+    // report the open parts of the cave as a grid of regions
+    let mut regions = vec![];
+    for x in (0..MAP_WIDTH).step_by(CAVE_REGION_SIZE as usize) {
+        for y in (0..MAP_HEIGHT).step_by(CAVE_REGION_SIZE as usize) {
+            let w = cmp::min(CAVE_REGION_SIZE, MAP_WIDTH - 1 - x);
+            let h = cmp::min(CAVE_REGION_SIZE, MAP_HEIGHT - 1 - y);
+            if w < 2 || h < 2 {
+                continue; // no room inside for anything
+            }
+            let region = Rect::new(x, y, w, h);
+            if open_spot(region, map).is_some() {
+                regions.push(region);
+            }
+        }
+    }
+    regions
+}
+
+/// One cellular automata step: tiles surrounded by walls become walls, tiles
+/// with few walls around become open, the rest stay as they are
+fn smooth_caves(map: &mut Map) {
+    let old_map = map.clone();
+    for x in 1..(MAP_WIDTH - 1) {
+        for y in 1..(MAP_HEIGHT - 1) {
+            let mut walls = 0;
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    if (dx, dy) != (0, 0) && old_map[(x + dx) as usize][(y + dy) as usize].blocked {
+                        walls += 1;
+                    }
+                }
+            }
+            if walls >= 5 {
+                map[x as usize][y as usize] = Tile::wall();
+            } else if walls <= 3 {
+                map[x as usize][y as usize] = Tile::empty();
+            }
+        }
+    }
+}
+
+fn make_bsp_rooms(map: &mut Map, rng: &mut GameRng) -> Vec<Rect> {
+    let mut rooms = vec![];
+    // keep the outer edge of the map solid, like the random rooms do
+    let whole_map = Rect::new(0, 0, MAP_WIDTH - 1, MAP_HEIGHT - 1);
+    bsp_split(whole_map, map, &mut rooms, rng);
+    rooms
+}
+
+/// Split an area in two until it is too small, put a room in each leaf and
+/// link the two halves. Returns a point inside the area's rooms to link to
+fn bsp_split(area: Rect, map: &mut Map, rooms: &mut Vec<Rect>, rng: &mut GameRng) -> (i32, i32) {
+    let width = area.x2 - area.x1;
+    let height = area.y2 - area.y1;
+    let can_split_x = width >= 2 * BSP_MIN_LEAF_SIZE;
+    let can_split_y = height >= 2 * BSP_MIN_LEAF_SIZE;
+
+    let split_x = match (can_split_x, can_split_y) {
+        (false, false) => {
+            // a leaf: carve a random room somewhere inside it
+            let w = rng.gen_range(ROOM_MIN_SIZE, cmp::min(ROOM_MAX_SIZE, width) + 1);
+            let h = rng.gen_range(ROOM_MIN_SIZE, cmp::min(ROOM_MAX_SIZE, height) + 1);
+            let x = rng.gen_range(area.x1, area.x2 - w + 1);
+            let y = rng.gen_range(area.y1, area.y2 - h + 1);
+            let room = Rect::new(x, y, w, h);
+            create_room(room, map);
+            rooms.push(room);
+            return room.center();
+        }
+        (true, false) => true,
+        (false, true) => false,
+        // cut across the longer side, so leaves stay roughly square
+        (true, true) if width > height => true,
+        (true, true) if height > width => false,
+        (true, true) => rng.gen(),
+    };
+
+    let (first, second) = if split_x {
+        let at = rng.gen_range(area.x1 + BSP_MIN_LEAF_SIZE, area.x2 - BSP_MIN_LEAF_SIZE + 1);
+        (Rect { x2: at, ..area }, Rect { x1: at, ..area })
+    } else {
+        let at = rng.gen_range(area.y1 + BSP_MIN_LEAF_SIZE, area.y2 - BSP_MIN_LEAF_SIZE + 1);
+        (Rect { y2: at, ..area }, Rect { y1: at, ..area })
+    };
+
+    let first_point = bsp_split(first, map, rooms, rng);
+    let second_point = bsp_split(second, map, rooms, rng);
+    connect_points(first_point, second_point, map, rng);
+    first_point
+}
+
+/// Advance to the next level, keeping the player and the inventory
+fn next_level(game: &mut Game, objects: &mut Vec<Object>) {
+    game.messages.add(
+        "You take a moment to rest, and recover your strength.",
+        VIOLET,
+    );
+    let heal_hp = objects[PLAYER].max_hp(game) / 2;
+    objects[PLAYER].heal(heal_hp, game);
+
+    game.messages.add(
+        "After a rare moment of peace, you descend deeper into \
+         the heart of the dungeon...",
+        RED,
+    );
+    descend(game, objects);
+}
+
+/// Generate the level below and put the player in it
+fn descend(game: &mut Game, objects: &mut Vec<Object>) {
+    if game.fov_settings.torch_radius < TORCH_RADIUS {
+        game.messages.add("You light a fresh torch.", LIGHT_YELLOW);
+        game.fov_settings.torch_radius = TORCH_RADIUS;
+    }
+    game.torch_fuel = TORCH_BURN_TURNS;
+    game.dungeon_level += 1;
+    game.map = make_map(
+        objects,
+        &game.monsters,
+        game.generator_params,
+        game.dungeon_level,
+        &mut game.rng,
+    );
+    initialise_fov(&game.map, &mut game.fov);
+    recompute_fov(game, objects);
+}
+
+/// Copy a single tile's sight and movement properties into the FOV map.
+/// Must be called whenever a tile changes after generation (a door opening,
+/// a wall being dug out, ...) so the FOV stays in sync with the map
+fn update_fov_tile(x: i32, y: i32, map: &Map, fov: &mut FovMap) {
+    let tile = &map[x as usize][y as usize];
+    fov.set(x, y, !tile.block_sight, !tile.blocked);
+}
+
+/// A blank FOV map, the size of the dungeon
+fn empty_fov() -> FovMap {
+    FovMap::new(MAP_WIDTH, MAP_HEIGHT)
+}
+
+/// Build the whole FOV map from a freshly generated map
+fn initialise_fov(map: &Map, fov: &mut FovMap) {
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            update_fov_tile(x, y, map, fov);
+        }
+    }
+}
+
+/// Recompute the light and the player's FOV, and mark everything the player
+/// sees as explored
+fn recompute_fov(game: &mut Game, objects: &[Object]) {
+    compute_light(game, objects);
+
+    // no radius: how far the player sees is up to the light
+    let player = &objects[PLAYER];
+    let settings = game.fov_settings;
+    game.fov.compute_fov(
+        player.x,
+        player.y,
+        0,
+        settings.light_walls,
+        settings.algorithm.algorithm(),
+    );
+
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            if game.is_visible(x, y) {
+                // as its visible, it means that it has been explored
+                game.map[x as usize][y as usize].explored = true;
+            }
+        }
+    }
+}
+
+/// Change how the FOV is computed, and recompute it right away
+fn set_fov_settings(settings: FovSettings, game: &mut Game, objects: &[Object]) {
+    game.fov_settings = settings;
+    recompute_fov(game, objects);
+}
+
+/// The torch burns down a step every `TORCH_BURN_TURNS` turns, the player
+/// seeing less and less until they find a new one on the next level
+fn burn_torch(game: &mut Game, objects: &[Object]) {
+    game.torch_fuel = game.torch_fuel.saturating_sub(1);
+    if game.torch_fuel > 0 || game.fov_settings.torch_radius <= TORCH_MIN_RADIUS {
+        return;
+    }
+    game.torch_fuel = TORCH_BURN_TURNS;
+    let settings = FovSettings {
+        torch_radius: game.fov_settings.torch_radius - 1,
+        ..game.fov_settings
+    };
+    set_fov_settings(settings, game, objects);
+    game.messages.add("Your torch burns lower.", LIGHT_GREY);
+}
+
+/// Add up the light of lit rooms, the player's torch and every light source
+/// on the map. Each source's reach is worked out on the FOV map, so this
+/// goes before the player's own FOV
+fn compute_light(game: &mut Game, objects: &[Object]) {
+    let mut light = vec![vec![BLACK; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    for x in 0..MAP_WIDTH {
+        for y in 0..MAP_HEIGHT {
+            if game.map[x as usize][y as usize].lit {
+                light[x as usize][y as usize] = ROOM_LIGHT;
+            }
+        }
+    }
+
+    let torch = Light {
+        color: TORCH_COLOR,
+        radius: game.fov_settings.torch_radius,
+    };
+    let sources = objects
+        .iter()
+        .filter_map(|object| object.light.map(|light| (object, light)))
+        .chain(std::iter::once((&objects[PLAYER], torch)));
+    for (object, source) in sources {
+        if source.radius <= 0 {
+            continue; // burnt out, or a radius of 0 would mean no limit
+        }
+        game.fov.compute_fov(
+            object.x,
+            object.y,
+            source.radius,
+            true,
+            game.fov_settings.algorithm.algorithm(),
+        );
+        for x in cmp::max(0, object.x - source.radius)..=cmp::min(MAP_WIDTH - 1, object.x + source.radius) {
+            for y in cmp::max(0, object.y - source.radius)..=cmp::min(MAP_HEIGHT - 1, object.y + source.radius) {
+                let falloff = 1.0 - object.distance(x, y) / (source.radius + 1) as f32;
+                if falloff > 0.0 && game.fov.is_in_fov(x, y) {
+                    light[x as usize][y as usize] = light[x as usize][y as usize] + source.color * falloff;
+                }
+            }
+        }
+    }
+    game.light = light;
+}
+
+/// Value given on the command line as `<name> <value>`, if any. A missing or
+/// malformed value exits with an error rather than being ignored
+fn arg_value<T: FromStr>(name: &str) -> Option<T>
+where
+    T::Err: fmt::Display,
+{
+    let args: Vec<String> = std::env::args().collect();
+    let i = args.iter().position(|arg| arg == name)?;
+    let value = args.get(i + 1).map(|value| value.parse::<T>());
+    match value {
+        Some(Ok(value)) => Some(value),
+        Some(Err(e)) => {
+            eprintln!("Invalid {}: {}", name, e);
+            std::process::exit(1);
+        }
+        None => {
+            eprintln!("Missing value for {}", name);
+            std::process::exit(1);
+        }
+    }
 }
 
-fn make_map(objects: &mut Vec<Object>) -> Map {
-    // blocked tiles filled
-    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+/// Create the player, the map and its FOV: a brand new game, no window needed.
+/// The same seed always generates the same dungeon
+fn new_game(seed: u64, monsters: Vec<MonsterTemplate>, generator_params: GeneratorParams) -> (Game, Vec<Object>) {
+    let mut rng = GameRng::seed_from_u64(seed);
 
-    let mut rooms = vec![];
+    // create player
+    let mut player = Object::new(0, 0, '@', "player", WHITE, true);
+    player.alive = true ;
+    player.fighter = Some( Fighter {
+        base_max_hp: 30, 
+        hp: 30, 
+        base_defence: 2, 
+        base_power: 5,
+        xp: 0,
+        on_death: DeathCallback::Player,
+    });
 
-    for _ in 0..MAX_ROOMS {
-        // random width and height
-        let w = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        let h = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+    // list with all objects in the game
+    let mut objects = vec![player];
 
-        // random position without going out of the boundaries of the map
-        let x = rand::thread_rng().gen_range(0, MAP_WIDTH - w);
-        let y = rand::thread_rng().gen_range(0, MAP_HEIGHT - h);
+    let mut game = Game {
+        // make the map - not rendered though
+        map: make_map(&mut objects, &monsters, generator_params, 1, &mut rng),
+        messages: Messages::new(),
+        inventory: vec![],
+        dungeon_level: 1,
+        monsters,
+        generator_params,
+        fov: empty_fov(),
+        light: vec![],
+        fov_settings: FovSettings::default(),
+        torch_fuel: TORCH_BURN_TURNS,
+        seed,
+        rng,
+    };
 
-        let new_room = Rect::new(x, y, w, h);
+    // populate the FOV map, according to the generated map
+    initialise_fov(&game.map, &mut game.fov);
+    recompute_fov(&mut game, &objects);
 
-        // Checking intersection with other rooms
-        let failed = rooms
-            .iter()
-            .any(|other_room| new_room.intersects_with(other_room));
+    // a warm welcoming message!
+    game.messages.add(
+        "Welcome stranger! Prepare to perish in the Tombs of the Ancient Kings.",
+        RED,
+    );
+    game.messages.add(format!("Dungeon seed: {}", game.seed), LIGHT_GREY);
 
-        if !failed {
-            // going ahead and creating the room
-            create_room(new_room, &mut map);
+    (game, objects)
+}
 
-            // adding characters to the new_room
-            place_objects(new_room, &map, objects);
+/// What is written to the save file
+#[derive(Serialize)]
+struct SaveData<'a> {
+    version: u32,
+    game: &'a Game,
+    objects: &'a [Object],
+}
 
-            // center coordinates of the room
-            let (new_x, new_y) = new_room.center();
+/// What is read back from the save file
+#[derive(Deserialize)]
+struct LoadedData {
+    game: Game,
+    objects: Vec<Object>,
+}
 
-            if rooms.is_empty() {
-                // as this is the first room, player from the center of this room
-                objects[PLAYER].set_pos(new_x, new_y);
-            } else {
-                // connect all the other rooms with a tunnel
+fn save_game(game: &Game, objects: &[Object]) -> Result<(), Box<dyn Error>> {
+    let save_data = serde_json::to_string(&SaveData {
+        version: SAVE_VERSION,
+        game,
+        objects,
+    })?;
+    let mut file = File::create(SAVE_FILE)?;
+    file.write_all(save_data.as_bytes())?;
+    Ok(())
+}
 
-                let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
+fn load_game(monsters: Vec<MonsterTemplate>) -> Result<(Game, Vec<Object>), Box<dyn Error>> {
+    let mut json_save_state = String::new();
+    let mut file = File::open(SAVE_FILE)?;
+    file.read_to_string(&mut json_save_state)?;
+
+    // check the version first, an older save would not parse anyway
+    let save_data: serde_json::Value = serde_json::from_str(&json_save_state)?;
+    let version = save_data["version"].as_u64().unwrap_or(0);
+    if version != u64::from(SAVE_VERSION) {
+        return Err(format!(
+            "save file version {} is not supported (expected {})",
+            version, SAVE_VERSION
+        )
+        .into());
+    }
+    let LoadedData { mut game, objects } = serde_json::from_value(save_data)?;
+    // the FOV and everything else assume the map is exactly this big
+    let width = game.map.len();
+    let height = game.map.first().map_or(0, |column| column.len());
+    if width != MAP_WIDTH as usize
+        || game.map.iter().any(|column| column.len() != MAP_HEIGHT as usize)
+    {
+        return Err(format!(
+            "save file map is {}x{} (expected {}x{})",
+            width, height, MAP_WIDTH, MAP_HEIGHT
+        )
+        .into());
+    }
+    game.monsters = monsters;
 
-                // randomize the tunnel generation
+    // the FOV is not saved, rebuild it from the loaded map
+    initialise_fov(&game.map, &mut game.fov);
+    recompute_fov(&mut game, &objects);
+    game.messages.add("Welcome back, stranger!", RED);
 
-                if rand::random() {
-                    // move horizontally and then vertically
-                    create_h_tunnel(prev_x, new_x, prev_y, &mut map);
-                    create_v_tunnel(prev_y, new_y, new_x, &mut map);
-                } else {
-                    create_v_tunnel(prev_y, new_y, prev_x, &mut map);
-                    create_h_tunnel(prev_x, new_x, new_y, &mut map);
-                }
-            }
+    Ok((game, objects))
+}
 
-            rooms.push(new_room);
+/// Run the rest of the turn once the player has acted: monsters move and
+/// the FOV follows the player. Works the same with or without a window.
+/// Nothing happens on frames where the player did not take a turn
+fn play_turn(player_action: PlayerAction, game: &mut Game, objects: &mut [Object]) {
+    if player_action != PlayerAction::TookTurn {
+        return;
+    }
+
+    // monsters turn
+    if objects[PLAYER].alive {
+        burn_torch(game, objects);
+        monsters_take_turn(game, objects);
+
+        // the player is busy climbing over rubble, the monsters get another go
+        if objects[PLAYER].slowed {
+            objects[PLAYER].slowed = false;
+            monsters_take_turn(game, objects);
         }
     }
 
-    map
+    recompute_fov(game, objects);
 }
 
-// main render program
-fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recompute: bool) {
-    if fov_recompute {
-        // recompute FOV if needed ( the player moved or something)
-        let player = &objects[PLAYER];
-        tcod.fov
-            .compute_fov(player.x, player.y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+fn monsters_take_turn(game: &mut Game, objects: &mut [Object]) {
+    for id in 0..objects.len() {
+        if objects[id].ai.is_some() {
+            ai_take_turn(id, game, objects);
+        }
     }
+}
+
+// main render program
+fn render_all(tcod: &mut Tcod, game: &Game, objects: &[Object]) {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    tcod.camera = Camera::following(player_x, player_y);
+    let camera = tcod.camera;
+
     // Traverse and set the tile color
     for y in 0..MAP_HEIGHT {
         for x in 0..MAP_WIDTH {
-            let visible = tcod.fov.is_in_fov(x, y);
-
-            let wall = game.map[x as usize][y as usize].block_sight;
-            let color = match (visible, wall) {
-                // outside FOV:
-                (false, true) => COLOR_DARK_WALL,
-                (false, false) => COLOR_DARK_GROUND,
-                // inside FOV
-                (true, true) => COLOR_LIGHT_WALL,
-                (true, false) => COLOR_LIGHT_GROUND,
+            let (screen_x, screen_y) = match camera.to_screen(x, y) {
+                Some(pos) => pos,
+                None => continue,
             };
-            let explored = &mut game.map[x as usize][y as usize].explored;
-
-            if visible {
-                // as its visible, it means that it has been explored
-                *explored = true;
-            }
-
-            if *explored {
+            let visible = game.is_visible(x, y);
+
+            let tile = &game.map[x as usize][y as usize];
+            // closed doors are drawn like the wall they sit in
+            let terrain = if tile.block_sight { Terrain::Wall } else { tile.terrain };
+            let (lit, dark) = terrain.colors();
+            // what is in sight takes the color of the light falling on it
+            let color = if visible {
+                lit * game.light[x as usize][y as usize]
+            } else {
+                dark
+            };
+            if tile.explored {
                 tcod.con
-                    .set_char_background(x, y, color, BackgroundFlag::Set);
+                    .set_char_background(screen_x, screen_y, color, BackgroundFlag::Set);
+                if let Some(door) = tile.door {
+                    let (glyph, door_color) = match door {
+                        Door::Open => ('\'', COLOR_DOOR),
+                        Door::Closed => ('+', COLOR_DOOR),
+                        Door::Locked => ('+', GOLD),
+                    };
+                    tcod.con.set_default_foreground(door_color);
+                    tcod.con.put_char(screen_x, screen_y, glyph, BackgroundFlag::None);
+                } else if let Some((glyph, glyph_color)) = tile.terrain.glyph() {
+                    tcod.con.set_default_foreground(glyph_color);
+                    tcod.con.put_char(screen_x, screen_y, glyph, BackgroundFlag::None);
+                }
             }
         }
     }
 
-    // draw all objects
-    for object in objects {
-        object.draw(&mut tcod.con);
-    }
+    // only draw what the player can see, plus remembered landmarks like stairs
     let mut to_draw: Vec<_> = objects
         .iter()
-        .filter(|o| tcod.fov.is_in_fov(o.x, o.y))
+        .filter(|o| {
+            game.is_visible(o.x, o.y)
+                || (o.always_visible && game.map[o.x as usize][o.y as usize].explored)
+        })
         .collect();
     
     // sort so that non-blocking objects come first 
@@ -543,14 +2859,14 @@ fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recomput
     
     // draw the objects
     for object in &to_draw {
-            object.draw(&mut tcod.con);
+            object.draw(&mut tcod.con, camera);
     }
 
     // blit the contents of "con" to the root console and render
     blit(
         &tcod.con,
         (0, 0),
-        (MAP_WIDTH, MAP_HEIGHT),
+        (VIEW_WIDTH, VIEW_HEIGHT),
         &mut tcod.root,
         (0, 0),
         1.0,
@@ -563,10 +2879,40 @@ fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recomput
 
     // show the player stats
     let hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
-    let max_hp = objects[PLAYER].fighter.map_or(0, |f| f.max_hp);
+    let max_hp = objects[PLAYER].max_hp(game);
 
     render_bar(&mut tcod.panel, 1, 1, BAR_WIDTH, "HP", hp, max_hp, LIGHT_RED, DARKER_RED);
 
+    // and how far they are from the next level
+    let xp = objects[PLAYER].fighter.map_or(0, |f| f.xp);
+    let next_level_xp = level_up_xp(objects[PLAYER].level);
+    render_bar(&mut tcod.panel, 1, 2, BAR_WIDTH, "XP", xp, next_level_xp, LIGHT_BLUE, DARKER_BLUE);
+
+    tcod.panel.set_default_foreground(WHITE);
+    tcod.panel.print_ex(1, 3, BackgroundFlag::None, TextAlignment::Left,
+        format!("Depth: {}", game.dungeon_level),
+    );
+
+    // print the game messages, one line at a time, newest at the bottom
+    let mut y = MSG_HEIGHT as i32;
+    for &(ref msg, color) in game.messages.iter().rev() {
+        // long messages wrap, so find out how many lines this one needs
+        let msg_height = tcod.panel.get_height_rect(MSG_X, y, MSG_WIDTH, 0, msg);
+        y -= msg_height;
+        if y < 0 {
+            // the rest has scrolled off the top of the panel
+            break;
+        }
+        tcod.panel.set_default_foreground(color);
+        tcod.panel.print_rect(MSG_X, y, MSG_WIDTH, 0, msg);
+    }
+
+    // name whatever is under the mouse
+    tcod.panel.set_default_foreground(LIGHT_GREY);
+    tcod.panel.print_rect(1, 0, BAR_WIDTH, 1,
+        get_names_under_mouse(tcod.mouse, tcod.camera, game, objects),
+    );
+
     // blit the contents of `panel` to the root console
     blit(
         &tcod.panel,
@@ -579,6 +2925,279 @@ fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recomput
     );
 }
 
+/// Names of the objects under the mouse, as long as the player can see them
+fn get_names_under_mouse(mouse: Mouse, camera: Camera, game: &Game, objects: &[Object]) -> String {
+    let (x, y) = match camera.to_map(mouse.cx as i32, mouse.cy as i32) {
+        Some(pos) => pos,
+        None => return String::new(),
+    };
+    if !game.is_visible(x, y) {
+        return String::new();
+    }
+
+    objects
+        .iter()
+        .filter(|object| object.pos() == (x, y))
+        .map(|object| object.name.clone())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Move a cursor over the map with the movement keys, describing what is
+/// under it, until Escape is pressed
+fn look_mode(tcod: &mut Tcod, game: &Game, objects: &[Object]) {
+    let (mut x, mut y) = objects[PLAYER].pos();
+    loop {
+        tcod.con.clear();
+        render_all(tcod, game, objects);
+
+        // highlight the cursor and describe what is under it, over the top of the map
+        if let Some((screen_x, screen_y)) = tcod.camera.to_screen(x, y) {
+            tcod.root
+                .set_char_background(screen_x, screen_y, LIGHT_YELLOW, BackgroundFlag::Set);
+        }
+        tcod.root.set_default_foreground(WHITE);
+        tcod.root.print_rect(1, 0, SCREEN_WIDTH - 2, 0, describe_tile(x, y, game, objects));
+        tcod.root.flush();
+
+        let key = tcod.root.wait_for_keypress(true);
+        if key.code == tcod::input::KeyCode::Escape {
+            return;
+        }
+        let step = tcod.keys.command_for(key).and_then(Command::direction);
+        if let Some((dx, dy)) = step {
+            // the cursor stays on screen, the camera only follows the player
+            if tcod.camera.to_screen(x + dx, y + dy).is_some() {
+                x += dx;
+                y += dy;
+            }
+        }
+    }
+}
+
+/// What the player knows of a tile: the ground, and whatever they can see on
+/// it, with how hurt each creature looks
+fn describe_tile(x: i32, y: i32, game: &Game, objects: &[Object]) -> String {
+    let tile = &game.map[x as usize][y as usize];
+    if !tile.explored {
+        return "You have not been here.".into();
+    }
+    if !game.is_visible(x, y) {
+        return format!("{} (remembered)", tile.name());
+    }
+
+    let seen: Vec<String> = objects
+        .iter()
+        .filter(|object| object.pos() == (x, y))
+        .map(|object| match object.fighter {
+            Some(fighter) if object.alive => format!(
+                "{} ({})",
+                object.name,
+                health_state(fighter.hp, object.max_hp(game))
+            ),
+            _ => object.name.clone(),
+        })
+        .collect();
+    if seen.is_empty() {
+        tile.name().into()
+    } else {
+        format!("{}: {}", tile.name(), seen.join(", "))
+    }
+}
+
+/// How hurt a creature looks
+fn health_state(hp: i32, max_hp: i32) -> &'static str {
+    let health = hp as f32 / max_hp as f32;
+    if health >= 1.0 {
+        "unhurt"
+    } else if health > 0.5 {
+        "wounded"
+    } else if health > 0.25 {
+        "badly wounded"
+    } else {
+        "almost dead"
+    }
+}
+
+/// Experience the player needs to reach the next level
+fn level_up_xp(level: i32) -> i32 {
+    LEVEL_UP_BASE + level * LEVEL_UP_FACTOR
+}
+
+/// Let the player pick a stat to raise for every level-up their experience allows
+fn level_up(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    let player = &mut objects[PLAYER];
+    // see if the player's experience is enough to level-up
+    while player.fighter.is_some_and(|f| f.xp >= level_up_xp(player.level)) {
+        // it is! level up
+        let required_xp = level_up_xp(player.level);
+        player.level += 1;
+        game.messages.add(
+            format!(
+                "Your battle skills grow stronger! You reached level {}!",
+                player.level
+            ),
+            YELLOW,
+        );
+
+        let fighter = player.fighter.as_mut().unwrap();
+        let mut choice = None;
+        while choice.is_none() {
+            // keep asking until a choice is made
+            choice = menu(
+                "Level up! Choose a stat to raise:\n",
+                &[
+                    format!("Constitution (+20 HP, from {})", fighter.base_max_hp),
+                    format!("Strength (+1 attack, from {})", fighter.base_power),
+                    format!("Agility (+1 defence, from {})", fighter.base_defence),
+                ],
+                LEVEL_SCREEN_WIDTH,
+                &mut tcod.root,
+            );
+        }
+        fighter.xp -= required_xp;
+        match choice.unwrap() {
+            0 => {
+                fighter.base_max_hp += 20;
+                fighter.hp += 20;
+            }
+            1 => {
+                fighter.base_power += 1;
+            }
+            2 => {
+                fighter.base_defence += 1;
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Show a list of options over the map and wait for the player to pick one
+/// by its letter. Any other key cancels
+fn menu<T: AsRef<str>>(header: &str, options: &[T], width: i32, root: &mut Root) -> Option<usize> {
+    assert!(
+        options.len() <= INVENTORY_SIZE,
+        "Cannot have a menu with more than {} options.",
+        INVENTORY_SIZE
+    );
+
+    // calculate total height for the header (after auto-wrap) and one line per option
+    let header_height = if header.is_empty() {
+        0
+    } else {
+        root.get_height_rect(0, 0, width, SCREEN_HEIGHT, header)
+    };
+    let height = options.len() as i32 + header_height;
+
+    // create an off-screen console that represents the menu's window
+    let mut window = Offscreen::new(width, height);
+
+    // print the header, with auto-wrap
+    window.set_default_foreground(WHITE);
+    window.print_rect_ex(0, 0, width, height, BackgroundFlag::None, TextAlignment::Left, header);
+
+    // print all the options
+    for (index, option_text) in options.iter().enumerate() {
+        let menu_letter = (b'a' + index as u8) as char;
+        let text = format!("({}) {}", menu_letter, option_text.as_ref());
+        window.print_ex(0, header_height + index as i32, BackgroundFlag::None, TextAlignment::Left,
+            text,
+        );
+    }
+
+    // blit the contents of "window" to the root console, over the map
+    let x = SCREEN_WIDTH / 2 - width / 2;
+    let y = SCREEN_HEIGHT / 2 - height / 2;
+    blit(&window, (0, 0), (width, height), root, (x, y), 1.0, 0.7);
+
+    // present the root console to the player and wait for a key-press
+    root.flush();
+    let key = root.wait_for_keypress(true);
+
+    // convert the ASCII code to an index; if it corresponds to an option, return it
+    if key.printable.is_alphabetic() {
+        let index = key.printable.to_ascii_lowercase() as usize - 'a' as usize;
+        if index < options.len() {
+            Some(index)
+        } else {
+            None
+        }
+    } else {
+        None
+    }
+}
+
+/// Change the FOV settings, showing each change on the map straight away.
+/// Picking an option cycles it, any other key leaves the menu
+fn options_menu(tcod: &mut Tcod, game: &mut Game, objects: &[Object]) {
+    loop {
+        tcod.con.clear();
+        render_all(tcod, game, objects);
+
+        let settings = game.fov_settings;
+        let options = [
+            format!("FOV algorithm: {}", settings.algorithm),
+            format!("Light walls: {}", if settings.light_walls { "on" } else { "off" }),
+            format!("Torch radius: {}", settings.torch_radius),
+        ];
+        let choice = menu(
+            "Options: pick one to change it, or any other key to go back.\n",
+            &options,
+            OPTIONS_WIDTH,
+            &mut tcod.root,
+        );
+        let settings = match choice {
+            Some(0) => FovSettings {
+                algorithm: settings.algorithm.next(),
+                ..settings
+            },
+            Some(1) => FovSettings {
+                light_walls: !settings.light_walls,
+                ..settings
+            },
+            Some(2) => FovSettings {
+                torch_radius: if settings.torch_radius >= TORCH_MAX_RADIUS {
+                    TORCH_MIN_RADIUS
+                } else {
+                    settings.torch_radius + 1
+                },
+                ..settings
+            },
+            _ => break,
+        };
+        set_fov_settings(settings, game, objects);
+    }
+}
+
+fn inventory_menu(inventory: &[Object], header: &str, root: &mut Root) -> Option<usize> {
+    // show a menu with each item of the inventory as an option
+    let options: Vec<String> = if inventory.is_empty() {
+        vec!["Inventory is empty.".into()]
+    } else {
+        inventory
+            .iter()
+            .map(|item| {
+                // show additional information, in case it's equipped
+                match item.equipment {
+                    Some(equipment) if equipment.equipped => {
+                        format!("{} (on {})", item.name, equipment.slot)
+                    }
+                    _ => item.name.clone(),
+                }
+            })
+            .collect()
+    };
+
+    let inventory_index = menu(header, &options, INVENTORY_WIDTH, root);
+
+    // if an item was chosen, return it
+    if !inventory.is_empty() {
+        inventory_index
+    } else {
+        None
+    }
+}
+
 fn render_bar( panel: &mut Offscreen, x: i32, y: i32, total_width: i32,
             name: &str, value: i32, maximum: i32, bar_color: Color, back_color: Color,) {
 
@@ -603,12 +3222,10 @@ fn render_bar( panel: &mut Offscreen, x: i32, y: i32, total_width: i32,
 }
 
 // define the behaviour of the keys for control
-fn handle_keys(tcod: &mut Tcod, game: &Game, objects: &mut Vec<Object>) -> PlayerAction {
-    // TODO: handle keys
-    use tcod::input::Key;
+fn handle_keys(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
     use tcod::input::KeyCode::*;
 
-    let key = tcod.root.wait_for_keypress(true);
+    let key = tcod.key;
     let player_alive = objects[PLAYER].alive;
     match (key, key.text(), player_alive) {
 
@@ -629,29 +3246,132 @@ fn handle_keys(tcod: &mut Tcod, game: &Game, objects: &mut Vec<Object>) -> Playe
 
         (Key { code: Escape, .. },_,_,) => Exit, // exit game
 
-        // movement keys
-        (Key { code: Up, .. }, _, true) => {
-            player_move_or_attack(0, -1, game, objects);
-            TookTurn
-        }
-        (Key { code: Down, .. },_,true) => {
-            player_move_or_attack(0, 1, game, objects);
-            TookTurn
+        // everything else goes through the key bindings
+        (_, _, true) => match tcod.keys.command_for(key) {
+            Some(command) => do_command(command, tcod, game, objects),
+            None => DidntTakeTurn,
         },
-        (Key { code: Left, .. },_,true) => {
-            player_move_or_attack(-1, 0, game, objects);
-            TookTurn
+
+        _ => DidntTakeTurn,
+    }
+}
+
+/// carry out a bound command for the (living) player
+fn do_command(command: Command, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    use Command::*;
+
+    // movement keys
+    if let Some((dx, dy)) = command.direction() {
+        player_move_or_attack(dx, dy, game, objects);
+        return TookTurn;
+    }
+
+    match command {
+        Wait => TookTurn, // let the monsters have a go
+
+        CloseDoor => {
+            if close_door(game, objects) {
+                TookTurn
+            } else {
+                DidntTakeTurn
+            }
+        }
+
+        Options => {
+            options_menu(tcod, game, objects);
+            DidntTakeTurn
+        }
+
+        Look => {
+            look_mode(tcod, game, objects);
+            DidntTakeTurn
+        }
+
+        PickUp => {
+            // pick up an item
+            let item_id = objects
+                .iter()
+                .position(|object| object.pos() == objects[PLAYER].pos() && object.item.is_some());
+            match item_id {
+                Some(item_id) => {
+                    pick_up_item(item_id, game, objects);
+                    TookTurn
+                }
+                None => DidntTakeTurn,
+            }
+        }
+
+        Descend => {
+            // go down stairs, if the player is on them
+            let player_on_stairs = objects
+                .iter()
+                .any(|object| object.pos() == objects[PLAYER].pos() && object.name == "stairs");
+            if player_on_stairs {
+                next_level(game, objects);
+            }
+            DidntTakeTurn
+        }
+
+        Inventory => {
+            // show the inventory: if an item is selected, use it
+            let inventory_index = inventory_menu(
+                &game.inventory,
+                "Press the key next to an item to use it, or any other to cancel.\n",
+                &mut tcod.root,
+            );
+            match inventory_index {
+                Some(inventory_index) => {
+                    use_item(inventory_index, game, objects);
+                    TookTurn
+                }
+                None => DidntTakeTurn,
+            }
         }
-        (Key { code: Right, .. },_,true) => {
-            player_move_or_attack(1, 0, game, objects);
-            TookTurn
+
+        Drop => {
+            // show the inventory; if an item is selected, drop it
+            let inventory_index = inventory_menu(
+                &game.inventory,
+                "Press the key next to an item to drop it, or any other to cancel.\n",
+                &mut tcod.root,
+            );
+            match inventory_index {
+                Some(inventory_index) => {
+                    drop_item(inventory_index, game, objects);
+                    TookTurn
+                }
+                None => DidntTakeTurn,
+            }
         }
 
+        // movement was handled above
         _ => DidntTakeTurn,
     }
 }
 
 fn main() {
+    // a broken monsters file is a bug for the designers, not something to play around
+    let monsters = load_monsters(MONSTERS_FILE).unwrap_or_else(|e| {
+        eprintln!("Invalid monster definitions: {}", e);
+        std::process::exit(1);
+    });
+
+    // `--generator rooms|bsp|caves`: lay out every level of a new game the same way
+    let generator_params = GeneratorParams {
+        generator: arg_value("--generator"),
+        ..GeneratorParams::default()
+    };
+    // `--seed <n>`: replay the dungeon of that seed. Read before opening the
+    // window, so a bad value stops the game straight away
+    let seed = arg_value::<u64>("--seed");
+
+    // `--check-maps <count>`: check the map generators over that many seeds, no window
+    if let Some(count) = arg_value::<u64>("--check-maps") {
+        let failures = check_maps(count, &monsters, generator_params);
+        println!("{} broken maps over {} seeds", failures, count);
+        std::process::exit(if failures == 0 { 0 } else { 1 });
+    }
+
     tcod::system::set_fps(LIMIT_FPS);
 
     let root = Root::initializer()
@@ -663,57 +3383,332 @@ fn main() {
 
     let mut tcod = Tcod {
         root,
-        con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
+        con: Offscreen::new(VIEW_WIDTH, VIEW_HEIGHT),
         panel: Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT),
-        fov: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
+        camera: Camera::default(),
+        key: Default::default(),
+        mouse: Default::default(),
+        keys: Keybindings::load(KEYBINDINGS_FILE).unwrap_or_else(|e| {
+            eprintln!("Could not load {}: {}, using the default keys", KEYBINDINGS_FILE, e);
+            Keybindings::default()
+        }),
     };
 
-    // create player
-    let mut player = Object::new(0, 0, '@', "player", WHITE, true);
-    player.alive = true ;
-    player.fighter = Some( Fighter {
-        max_hp: 30, 
-        hp: 30, 
-        defence: 2, 
-        power: 5,
-        on_death: DeathCallback::Player,
-    });
-
-    // list with all objects in the game
-    let mut objects = vec![player];
-
-    let mut game = Game {
-        // make the map - not rendered though
-        map: make_map(&mut objects),
+    // pick up the saved run, unless a seed or generator asks for a specific new dungeon
+    let new_dungeon = seed.is_some() || generator_params.generator.is_some();
+    let has_save = fs::metadata(SAVE_FILE).is_ok();
+    let saved = if !new_dungeon && has_save {
+        load_game(monsters.clone())
+            .map_err(|e| eprintln!("Could not load the saved game: {}", e))
+            .ok()
+    } else {
+        None
     };
-
-    // first time FOV recomputation
-    let mut previous_player_pos = (-1, -1);
+    let (mut game, mut objects) = match saved {
+        Some(saved) => saved,
+        // use the seed from the command line to replay a dungeon, or roll a new one
+        None => new_game(seed.unwrap_or_else(rand::random), monsters, generator_params),
+    };
+    // a replayed seed or forced generator must not overwrite the saved run
+    let owns_save = !(new_dungeon && has_save);
+    if !owns_save {
+        eprintln!("Keeping the saved game: this run will not be saved");
+    }
 
     while !tcod.root.window_closed() {
         // Clear previous frame
         tcod.con.clear();
 
         // render
-        let fov_recompute = previous_player_pos != (objects[PLAYER].x, objects[PLAYER].y);
-        render_all(&mut tcod, &mut game, &objects, fov_recompute);
+        render_all(&mut tcod, &game, &objects);
 
         tcod.root.flush();
 
+        // the frame goes on whether or not anything was pressed, so the
+        // mouse can be followed
+        tcod.key = Default::default();
+        match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
+            Some((_, Event::Mouse(mouse))) => tcod.mouse = mouse,
+            Some((_, Event::Key(key))) => tcod.key = key,
+            _ => {}
+        }
+
         // handle keys and exit game if needed
-        previous_player_pos = objects[PLAYER].pos();
-        let player_action = handle_keys(&mut tcod, &game, &mut objects);
+        let player_action = handle_keys(&mut tcod, &mut game, &mut objects);
         if player_action == PlayerAction::Exit {
             break;
         }
 
-        // monsters turn
-        if objects[PLAYER].alive && player_action != PlayerAction::DidntTakeTurn {
-            for id in 0..objects.len() {
-                if objects[id].ai.is_some() {
-                    ai_take_turn(id, &tcod, &game, &mut objects);
-                }
+        play_turn(player_action, &mut game, &mut objects);
+        level_up(&mut tcod, &mut game, &mut objects);
+
+        // a roguelike has no second chances: death deletes the save
+        if owns_save && !objects[PLAYER].alive && fs::metadata(SAVE_FILE).is_ok() {
+            if let Err(e) = fs::remove_file(SAVE_FILE) {
+                eprintln!("Could not delete the saved game: {}", e);
             }
         }
     }
+
+    // keep the run for next time, as long as the player survived it
+    if owns_save && objects[PLAYER].alive {
+        if let Err(e) = save_game(&game, &objects) {
+            eprintln!("Could not save the game: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a map with no walls at all
+    fn open_map() -> Map {
+        vec![vec![Tile::empty(); MAP_HEIGHT as usize]; MAP_WIDTH as usize]
+    }
+
+    fn test_game(seed: u64) -> (Game, Vec<Object>) {
+        let monsters = load_monsters(MONSTERS_FILE).expect("the monsters file is valid");
+        new_game(seed, monsters, GeneratorParams::default())
+    }
+
+    /// the key press event for a character, as both input APIs report it
+    fn key_press(c: char) -> Key {
+        let mut key = Key::default();
+        key.code = tcod::input::KeyCode::Char;
+        key.printable = c;
+        key.pressed = true;
+        key
+    }
+
+    #[test]
+    fn vision_stops_at_walls() {
+        // a wall right across the map, one step east of the viewer
+        let mut map = open_map();
+        for y in 0..MAP_HEIGHT {
+            map[6][y as usize] = Tile::wall();
+        }
+        let mut fov = empty_fov();
+        initialise_fov(&map, &mut fov);
+        fov.compute_fov(5, 5, 0, true, FovAlgorithm::Basic);
+
+        assert!(fov.is_in_fov(4, 5));
+        assert!(fov.is_in_fov(6, 5), "the wall itself is seen");
+        assert!(!fov.is_in_fov(7, 5), "nothing behind the wall is");
+        assert!(!fov.is_in_fov(20, 5));
+    }
+
+    #[test]
+    fn fov_follows_doors() {
+        let (mut game, _) = test_game(0);
+        game.map = open_map();
+        for y in 0..MAP_HEIGHT {
+            game.map[6][y as usize] = Tile::wall();
+        }
+        game.map[6][5] = Tile::door(Door::Closed);
+        initialise_fov(&game.map, &mut game.fov);
+
+        game.fov.compute_fov(5, 5, 0, true, FovAlgorithm::Basic);
+        assert!(!game.fov.is_in_fov(7, 5), "a closed door blocks sight");
+
+        set_door(6, 5, Door::Open, &mut game);
+        game.fov.compute_fov(5, 5, 0, true, FovAlgorithm::Basic);
+        assert!(game.fov.is_in_fov(7, 5), "an open one does not");
+
+        set_door(6, 5, Door::Closed, &mut game);
+        game.fov.compute_fov(5, 5, 0, true, FovAlgorithm::Basic);
+        assert!(!game.fov.is_in_fov(7, 5));
+    }
+
+    #[test]
+    fn only_swimmers_path_through_deep_water() {
+        // a river right across the map, between the monster and its target
+        let mut map = open_map();
+        for y in 0..MAP_HEIGHT {
+            map[6][y as usize] = Tile::new(Terrain::DeepWater);
+        }
+        let mut objects = vec![
+            Object::new(7, 5, '@', "player", WHITE, true),
+            Object::new(5, 5, 'c', "crocodile", WHITE, true),
+        ];
+
+        assert_eq!(next_step_towards(1, (7, 5), &map, &objects), None);
+        objects[1].swims = true;
+        assert_eq!(next_step_towards(1, (7, 5), &map, &objects), Some((6, 5)));
+    }
+
+    #[test]
+    fn misspelled_monster_keys_are_rejected() {
+        let monster = r#"
+            [[monster]]
+            name = "eel"
+            glyph = "e"
+            color = { r = 0, g = 0, b = 255 }
+            max_hp = 5
+            defence = 0
+            power = 2
+            xp = 10
+            vision_radius = 4
+            ai = "wandering"
+            spawn_weight = [{ level = 1, value = 10 }]
+            min_depth = 1
+        "#;
+        assert!(toml::from_str::<MonstersFile>(monster).is_ok());
+        assert!(toml::from_str::<MonstersFile>(&format!("{}swim = true", monster)).is_err());
+        let light = "light = { color = { r = 0, g = 0, b = 255 }, radious = 2 }";
+        assert!(toml::from_str::<MonstersFile>(&format!("{}{}", monster, light)).is_err());
+    }
+
+    #[test]
+    fn generated_maps_are_connected() {
+        let monsters = load_monsters(MONSTERS_FILE).expect("the monsters file is valid");
+        assert_eq!(check_maps(50, &monsters, GeneratorParams::default()), 0);
+    }
+
+    /// the full sweep `--check-maps` does; run with `cargo test --release -- --ignored`
+    #[test]
+    #[ignore]
+    fn generated_maps_are_connected_for_many_seeds() {
+        let monsters = load_monsters(MONSTERS_FILE).expect("the monsters file is valid");
+        assert_eq!(check_maps(2000, &monsters, GeneratorParams::default()), 0);
+    }
+
+    #[test]
+    fn turns_play_out_without_a_window() {
+        let positions = |objects: &[Object]| objects.iter().map(Object::pos).collect::<Vec<_>>();
+
+        // the same seed and the same turns give the same game, no Root involved
+        let (mut game, mut objects) = test_game(42);
+        let (mut replay, mut replay_objects) = test_game(42);
+        for _ in 0..20 {
+            play_turn(PlayerAction::TookTurn, &mut game, &mut objects);
+            play_turn(PlayerAction::TookTurn, &mut replay, &mut replay_objects);
+        }
+        assert_eq!(positions(&objects), positions(&replay_objects));
+
+        let (x, y) = objects[PLAYER].pos();
+        assert!(game.fov.is_in_fov(x, y));
+        assert!(game.map[x as usize][y as usize].explored);
+    }
+
+    #[test]
+    fn item_keys_reach_their_commands() {
+        let keys = Keybindings::default();
+        assert_eq!(keys.command_for(key_press('g')), Some(Command::PickUp));
+        assert_eq!(keys.command_for(key_press('i')), Some(Command::Inventory));
+        assert_eq!(keys.command_for(key_press('d')), Some(Command::Drop));
+
+        // the text input that follows a key press must not fire it again
+        let mut text = Key::default();
+        text.code = tcod::input::KeyCode::Text;
+        assert_eq!(keys.command_for(text), None);
+    }
+
+    #[test]
+    fn pick_up_refuses_a_full_inventory() {
+        let (mut game, mut objects) = test_game(0);
+        let (x, y) = objects[PLAYER].pos();
+        game.inventory = (0..INVENTORY_SIZE).map(|_| make_item(Item::Heal, x, y)).collect();
+        objects.push(make_item(Item::Sword, x, y));
+
+        let sword = objects.len() - 1;
+        pick_up_item(sword, &mut game, &mut objects);
+        assert_eq!(game.inventory.len(), INVENTORY_SIZE);
+        assert_eq!(objects[sword].item, Some(Item::Sword), "the sword stays on the floor");
+
+        game.inventory.pop();
+        pick_up_item(sword, &mut game, &mut objects);
+        assert_eq!(game.inventory.len(), INVENTORY_SIZE);
+        assert_eq!(game.inventory.last().and_then(|item| item.item), Some(Item::Sword));
+    }
+
+    #[test]
+    fn dropped_items_land_under_the_player() {
+        let (mut game, mut objects) = test_game(0);
+        game.inventory.push(make_item(Item::Heal, 0, 0));
+
+        drop_item(0, &mut game, &mut objects);
+        assert!(game.inventory.is_empty());
+        let dropped = objects.last().unwrap();
+        assert_eq!(dropped.item, Some(Item::Heal));
+        assert_eq!(dropped.pos(), objects[PLAYER].pos());
+    }
+
+    #[test]
+    fn healing_is_only_used_up_when_hurt() {
+        let (mut game, mut objects) = test_game(0);
+        let max_hp = objects[PLAYER].max_hp(&game);
+        game.inventory.push(make_item(Item::Heal, 0, 0));
+
+        use_item(0, &mut game, &mut objects);
+        assert_eq!(game.inventory.len(), 1, "cancelled at full health");
+
+        objects[PLAYER].fighter.as_mut().unwrap().hp = 1;
+        use_item(0, &mut game, &mut objects);
+        assert!(game.inventory.is_empty());
+        assert_eq!(objects[PLAYER].fighter.unwrap().hp, cmp::min(1 + HEAL_AMOUNT, max_hp));
+    }
+
+    #[test]
+    fn health_is_described_in_words() {
+        assert_eq!(health_state(10, 10), "unhurt");
+        assert_eq!(health_state(6, 10), "wounded");
+        assert_eq!(health_state(5, 10), "badly wounded");
+        assert_eq!(health_state(3, 10), "badly wounded");
+        assert_eq!(health_state(2, 10), "almost dead");
+    }
+
+    #[test]
+    fn looking_only_lists_what_is_in_view() {
+        let (mut game, _) = test_game(0);
+        game.map = open_map();
+        for y in 0..MAP_HEIGHT {
+            game.map[6][y as usize] = Tile::wall();
+        }
+        for tile in game.map.iter_mut().flatten() {
+            tile.explored = true;
+        }
+        game.map[30][30].explored = false;
+        initialise_fov(&game.map, &mut game.fov);
+        game.fov.compute_fov(5, 5, 0, true, FovAlgorithm::Basic);
+        game.light = vec![vec![WHITE; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+
+        let monster = |x, y, name, hp| {
+            let mut monster = Object::new(x, y, 'o', name, WHITE, true);
+            monster.alive = true;
+            monster.fighter = Some(Fighter {
+                base_max_hp: 10,
+                hp,
+                base_defence: 0,
+                base_power: 0,
+                xp: 0,
+                on_death: DeathCallback::Monster,
+            });
+            monster
+        };
+        let objects = vec![
+            Object::new(5, 5, '@', "player", WHITE, true),
+            monster(4, 5, "orc", 2),
+            // behind the wall
+            monster(8, 5, "troll", 10),
+        ];
+
+        assert_eq!(describe_tile(4, 5, &game, &objects), "Floor: orc (almost dead)");
+        assert_eq!(describe_tile(8, 5, &game, &objects), "Floor (remembered)");
+        assert_eq!(describe_tile(30, 30, &game, &objects), "You have not been here.");
+    }
+
+    #[test]
+    fn vi_keys_move_the_look_cursor() {
+        let keys = Keybindings::default();
+        let step = |c| keys.command_for(key_press(c)).and_then(Command::direction);
+        assert_eq!(step('h'), Some((-1, 0)));
+        assert_eq!(step('j'), Some((0, 1)));
+        assert_eq!(step('k'), Some((0, -1)));
+        assert_eq!(step('l'), Some((1, 0)));
+        assert_eq!(step('y'), Some((-1, -1)));
+        assert_eq!(step('u'), Some((1, -1)));
+        assert_eq!(step('b'), Some((-1, 1)));
+        assert_eq!(step('n'), Some((1, 1)));
+    }
 }